@@ -0,0 +1,459 @@
+use std::{
+    cmp::Reverse,
+    error::Error,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::{
+    format::format_size,
+    gui,
+    parse_tree::{
+        self, Config, DirRef, FileError, FileErrorKind, GitFilter, Hardlinks, MetadataFields,
+        PathFilter, Scan, SizeKind, Strategy,
+    },
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Disk usage statistics viewer, after WinDirStat")]
+pub struct Cli {
+    /// Opens the GUI when no subcommand is given
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Scan a directory and print a summary, without opening a window
+    Scan {
+        path: PathBuf,
+        #[command(flatten)]
+        walk: WalkArgs,
+        /// Walker used to traverse the tree
+        #[arg(long, value_enum, default_value_t = Walker::Parallel)]
+        walker: Walker,
+        /// Number of the largest entries below PATH to list
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+        /// Print every error met during the scan
+        #[arg(long)]
+        errors: bool,
+        /// Print the whole scanned tree
+        #[arg(long)]
+        tree: bool,
+        /// Only list the entries git tracks, or those it ignores, with --top
+        /// and --tree
+        #[arg(long, value_enum, value_name = "ENTRIES", requires = "gitignore")]
+        only: Option<OnlyArg>,
+    },
+    /// Scan a directory with every walker and check that they agree
+    Compare {
+        path: PathBuf,
+        #[command(flatten)]
+        walk: WalkArgs,
+    },
+    /// Time the walkers on a directory, walking it several times with each
+    Bench {
+        path: PathBuf,
+        #[command(flatten)]
+        walk: WalkArgs,
+        /// Walker to time, every one by default. May be repeated
+        #[arg(long = "walker", value_enum)]
+        walkers: Vec<Walker>,
+        /// Timed walks per walker
+        #[arg(long, default_value_t = 5)]
+        runs: usize,
+    },
+    /// Open the GUI, scanning PATH right away if given
+    Gui {
+        path: Option<PathBuf>,
+        #[command(flatten)]
+        walk: WalkArgs,
+        /// Walker used to traverse the tree
+        #[arg(long, value_enum, default_value_t = Walker::Parallel)]
+        walker: Walker,
+    },
+}
+
+/// Options shared by every command which walks a tree.
+#[derive(Debug, Args)]
+struct WalkArgs {
+    /// Follow symbolic links to directories
+    #[arg(short = 'L', long)]
+    follow_symlinks: bool,
+    /// Descend into directories on other filesystems than PATH
+    #[arg(long)]
+    cross_filesystems: bool,
+    /// Size reported for files
+    #[arg(long, value_enum, default_value_t = SizeArg::Apparent)]
+    size: SizeArg,
+    /// How the size of files with several hard links is counted
+    #[arg(long, value_enum, default_value_t = HardlinksArg::Once)]
+    hardlinks: HardlinksArg,
+    /// Only keep files matching this glob, on their name or full path
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+    /// Leave out entries matching this glob, on their name or full path.
+    /// A trailing slash only matches directories, as in `target/`
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+    /// Still sum up the size of the entries left out
+    #[arg(long)]
+    count_excluded: bool,
+    /// Read .gitignore, .ignore and the global git excludes to tell which
+    /// entries git ignores
+    #[arg(long)]
+    gitignore: bool,
+    /// Only keep the entries up to this many levels below PATH, at least
+    /// one, summing up the size of the deeper ones
+    #[arg(long, value_name = "DEPTH")]
+    max_depth: Option<usize>,
+    /// Metadata to keep for each entry besides its size and modification
+    /// time. May be repeated or separated by commas
+    #[arg(long, value_enum, value_name = "FIELD", value_delimiter = ',')]
+    metadata: Vec<MetadataArg>,
+    /// Threads of the parallel walker, by default one per CPU. Fewer suit
+    /// spinning disks and network filesystems better
+    #[arg(short = 'j', long, value_name = "N")]
+    threads: Option<usize>,
+    /// Most directories read at the same time, to spare slow disks
+    #[arg(long, value_name = "N")]
+    max_reads: Option<usize>,
+}
+
+impl WalkArgs {
+    /// The scan of `path` set up by the options.
+    fn scan(&self, path: impl Into<PathBuf>) -> Result<Scan, globset::Error> {
+        let mut scan = Scan::new(path)
+            .follow_symlinks(self.follow_symlinks)
+            .same_filesystem(!self.cross_filesystems)
+            .size_kind(match self.size {
+                SizeArg::Apparent => SizeKind::Apparent,
+                SizeArg::Allocated => SizeKind::Allocated,
+            })
+            .hardlinks(match self.hardlinks {
+                HardlinksArg::Once => Hardlinks::CountOnce,
+                HardlinksArg::Proportional => Hardlinks::Proportional,
+            })
+            .filter(PathFilter::new(&self.include, &self.exclude)?)
+            .count_excluded(self.count_excluded)
+            .gitignore(self.gitignore)
+            .metadata(self.metadata_fields());
+        if let Some(depth) = self.max_depth {
+            // The walk of a summarised directory would summarise it again at 0
+            scan = scan.max_depth(depth.max(1));
+        }
+        if let Some(threads) = self.threads {
+            scan = scan.threads(threads.max(1));
+        }
+        if let Some(max_reads) = self.max_reads {
+            scan = scan.max_reads(max_reads.max(1));
+        }
+        Ok(scan)
+    }
+
+    /// The options alone, for the GUI which scans several paths with them.
+    fn config(&self) -> Result<Config, globset::Error> {
+        Ok(self.scan(PathBuf::new())?.config().clone())
+    }
+
+    fn metadata_fields(&self) -> MetadataFields {
+        let mut metadata = MetadataFields::default();
+        for field in &self.metadata {
+            match field {
+                MetadataArg::Accessed => metadata.accessed = true,
+                MetadataArg::Changed => metadata.changed = true,
+                MetadataArg::Uid => metadata.uid = true,
+                MetadataArg::Gid => metadata.gid = true,
+                MetadataArg::Mode => metadata.mode = true,
+                MetadataArg::Type => metadata.file_type = true,
+                MetadataArg::All => {
+                    metadata = MetadataFields {
+                        accessed: true,
+                        changed: true,
+                        uid: true,
+                        gid: true,
+                        mode: true,
+                        file_type: true,
+                    }
+                }
+            }
+        }
+        metadata
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum SizeArg {
+    /// Length of the file contents
+    Apparent,
+    /// Space allocated on disk
+    Allocated,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum HardlinksArg {
+    /// Count the size once, for the first link found
+    Once,
+    /// Split the size evenly between the links
+    Proportional,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum MetadataArg {
+    /// Time of the last access
+    Accessed,
+    /// Time of the last status change
+    Changed,
+    /// Owner user id
+    Uid,
+    /// Owner group id
+    Gid,
+    /// Permission bits
+    Mode,
+    /// Keep sockets, FIFOs and devices, tagged with their type
+    Type,
+    /// Every field above
+    All,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum OnlyArg {
+    /// Entries git doesn't ignore
+    Tracked,
+    /// Entries git ignores, and the directories holding some
+    Ignored,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Walker {
+    Serial,
+    Parallel,
+    Async,
+    #[cfg(target_os = "linux")]
+    Linux,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring,
+}
+
+impl From<Walker> for Strategy {
+    fn from(walker: Walker) -> Self {
+        match walker {
+            Walker::Serial => Strategy::Serial,
+            Walker::Parallel => Strategy::Parallel,
+            Walker::Async => Strategy::Async,
+            #[cfg(target_os = "linux")]
+            Walker::Linux => Strategy::Linux,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            Walker::Uring => Strategy::Uring,
+        }
+    }
+}
+
+impl Cli {
+    pub fn run(self) -> Result<(), Box<dyn Error>> {
+        match self.command {
+            None => gui::main(None, Config::new(false, true), Strategy::default())?,
+            Some(Command::Gui { path, walk, walker }) => {
+                gui::main(path, walk.config()?, walker.into())?
+            }
+            Some(Command::Scan {
+                path,
+                walk,
+                walker,
+                top,
+                errors,
+                tree,
+                only,
+            }) => {
+                let filter = match only {
+                    None => GitFilter::All,
+                    Some(OnlyArg::Tracked) => GitFilter::Tracked,
+                    Some(OnlyArg::Ignored) => GitFilter::Ignored,
+                };
+                let scan = walk.scan(&path)?;
+                let size_kind = scan.config().size_kind;
+                let start = Instant::now();
+                let (scanned, walk_errors) = scan.strategy(walker.into()).run()?;
+                let elapsed = start.elapsed();
+                let root = scanned.root();
+
+                if tree {
+                    parse_tree::print_tree(root, 0, filter);
+                    println!();
+                }
+                if errors {
+                    for err in &walk_errors {
+                        eprintln!("{}", err);
+                    }
+                }
+                let elapsed = elapsed.as_secs_f64();
+                print_summary(root, size_kind, &walk_errors, elapsed, top, filter);
+            }
+            Some(Command::Compare { path, walk }) => compare_walkers(&path, walk.scan(&path)?)?,
+            Some(Command::Bench {
+                path,
+                walk,
+                walkers,
+                runs,
+            }) => {
+                let strategies: Vec<Strategy> = if walkers.is_empty() {
+                    Strategy::ALL.to_vec()
+                } else {
+                    walkers.into_iter().map(Strategy::from).collect()
+                };
+                bench_walkers(walk.scan(&path)?, &strategies, runs.max(1))?
+            }
+        }
+        Ok(())
+    }
+}
+
+fn print_summary(
+    root: DirRef,
+    kind: SizeKind,
+    errors: &[FileError],
+    elapsed: f64,
+    top: usize,
+    filter: GitFilter,
+) {
+    let size = root.get_size();
+    println!("{}", root.get_name());
+    println!("  Apparent:   {}", format_size(size.total_size));
+    println!("  Allocated:  {}", format_size(size.total_allocated));
+    println!("  Files size: {}", format_size(size.files(kind)));
+    println!("  Items:      {}", size.item_count);
+    let ignored = root.ignored_size(kind);
+    if ignored > 0 {
+        println!("  Ignored:    {}", format_size(ignored));
+    }
+    if size.excluded_size > 0 || size.excluded_allocated > 0 {
+        println!("  Excluded:   {}", format_size(size.excluded(kind)));
+    }
+    let links = count_hardlinks(root);
+    if links > 0 {
+        println!("  Hard links: {}", links);
+    }
+    let loops = errors
+        .iter()
+        .filter(|err| err.kind == FileErrorKind::SymlinkLoop)
+        .count();
+    if loops > 0 {
+        println!("  Errors:     {} ({} symlink loops)", errors.len(), loops);
+    } else {
+        println!("  Errors:     {}", errors.len());
+    }
+    println!("  Elapsed:    {:.3} s", elapsed);
+
+    let mut largest: Vec<(u64, String)> = root
+        .get_dirs()
+        .filter(|d| filter.keeps_dir(*d))
+        .map(|d| (filter.dir_size(d, kind), format!("{}/", d.get_name())))
+        .chain(
+            root.get_files()
+                .filter(|f| filter.keeps_file(*f))
+                .map(|f| (f.get_size_as(kind), f.get_name().to_string())),
+        )
+        .collect();
+    largest.sort_by_key(|(size, _)| Reverse(*size));
+    if top > 0 && !largest.is_empty() {
+        println!();
+        for (size, name) in largest.iter().take(top) {
+            println!("  {:>12}  {}", format_size(*size), name);
+        }
+    }
+}
+
+/// Scans `path` with each strategy, failing on the first one whose tree or
+/// errors differ from those of the first strategy.
+fn compare_walkers(path: &Path, scan: Scan) -> Result<(), Box<dyn Error>> {
+    // Which link of a file is counted in full depends on the walk order
+    let scan = scan.hardlinks(Hardlinks::Proportional);
+    let mut results = Vec::new();
+    for &strategy in Strategy::ALL {
+        let start = Instant::now();
+        let (root, mut errors) = scan.clone().strategy(strategy).run()?;
+        println!("{:?}: {:.3} s", strategy, start.elapsed().as_secs_f64());
+        errors.sort_by(|a, b| a.file.cmp(&b.file));
+        results.push((strategy, root, errors));
+    }
+
+    let (first, first_root, first_errors) = &results[0];
+    for (strategy, root, errors) in &results[1..] {
+        if let Some(diff) = first_root.root().first_difference(root.root()) {
+            return Err(format!(
+                "{:?} and {:?} walkers differ at {}",
+                first,
+                strategy,
+                path.join(diff).display()
+            )
+            .into());
+        }
+        let same_errors = first_errors.len() == errors.len()
+            && first_errors
+                .iter()
+                .zip(errors)
+                .all(|(a, b)| a.file == b.file && a.kind == b.kind);
+        if !same_errors {
+            return Err(format!(
+                "{:?} and {:?} walkers report different errors",
+                first, strategy
+            )
+            .into());
+        }
+    }
+    println!("All walkers agree");
+    Ok(())
+}
+
+/// Times `runs` walks with each strategy, after an untimed one to warm up
+/// the caches.
+fn bench_walkers(scan: Scan, strategies: &[Strategy], runs: usize) -> Result<(), Box<dyn Error>> {
+    let (tree, _) = scan.clone().run()?;
+    let entries = tree.root().get_size().item_count;
+    println!(
+        "{}: {} entries, {} runs",
+        tree.root().get_name(),
+        entries,
+        runs
+    );
+    let (memory, nodes) = (tree.heap_size(), tree.node_count());
+    println!(
+        "  Memory:    {} for {} entries, {:.1} bytes/entry",
+        format_size(memory as u64),
+        nodes,
+        memory as f64 / nodes as f64
+    );
+    drop(tree);
+    for &strategy in strategies {
+        let mut times: Vec<f64> = (0..runs)
+            .map(|_| {
+                let start = Instant::now();
+                scan.clone()
+                    .strategy(strategy)
+                    .run()
+                    .map(|_| start.elapsed().as_secs_f64())
+            })
+            .collect::<Result<_, _>>()?;
+        times.sort_by(f64::total_cmp);
+        let median = times[times.len() / 2];
+        println!(
+            "  {:<10} min {:.3} s  median {:.3} s  {:>10.0} entries/s",
+            format!("{:?}", strategy),
+            times[0],
+            median,
+            entries as f64 / median
+        );
+    }
+    Ok(())
+}
+
+/// Number of files below `dir` which share their inode with other links.
+fn count_hardlinks(dir: DirRef) -> usize {
+    dir.get_files()
+        .filter(|f| f.get_hardlink().is_some())
+        .count()
+        + dir.get_dirs().map(count_hardlinks).sum::<usize>()
+}
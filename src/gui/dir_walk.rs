@@ -1,19 +1,322 @@
-use crate::parse_tree::Dir;
+use std::{
+    cmp::Ordering,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::parse_tree::{DirRef, FileKind, FileRef, MetadataFields};
 
 use super::treeview::TreeWalk;
 
-impl<'a> TreeWalk for &'a Dir {
-    const N_COLS: usize = 3;
+/// A row of the tree view. Children of a directory are its subdirectories
+/// followed by its files, which is also the order used by the treemap.
+#[derive(Clone, Copy)]
+pub enum DirEntry<'a> {
+    Dir(DirRef<'a>),
+    File(FileRef<'a>),
+}
+
+impl DirEntry<'_> {
+    fn name(&self) -> &str {
+        match self {
+            DirEntry::Dir(dir) => dir.get_name(),
+            DirEntry::File(file) => file.get_name(),
+        }
+    }
+
+    fn total_size(&self) -> u64 {
+        match self {
+            DirEntry::Dir(dir) => dir.get_size().total_size,
+            DirEntry::File(file) => file.get_size(),
+        }
+    }
+
+    fn allocated(&self) -> u64 {
+        match self {
+            DirEntry::Dir(dir) => dir.get_size().total_allocated,
+            DirEntry::File(file) => file.get_allocated(),
+        }
+    }
+
+    fn files_size(&self) -> u64 {
+        match self {
+            DirEntry::Dir(dir) => dir.get_size().files_size,
+            DirEntry::File(file) => file.get_size(),
+        }
+    }
+
+    fn item_count(&self) -> u64 {
+        match self {
+            DirEntry::Dir(dir) => dir.get_size().item_count,
+            DirEntry::File(_) => 0,
+        }
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        match self {
+            DirEntry::Dir(dir) => dir.get_modified(),
+            DirEntry::File(file) => file.get_modified(),
+        }
+    }
+
+    fn accessed(&self) -> Option<SystemTime> {
+        match self {
+            DirEntry::Dir(dir) => dir.get_accessed(),
+            DirEntry::File(file) => file.get_accessed(),
+        }
+    }
+
+    fn changed(&self) -> Option<SystemTime> {
+        match self {
+            DirEntry::Dir(dir) => dir.get_changed(),
+            DirEntry::File(file) => file.get_changed(),
+        }
+    }
+
+    fn uid(&self) -> Option<u32> {
+        match self {
+            DirEntry::Dir(dir) => dir.get_uid(),
+            DirEntry::File(file) => file.get_uid(),
+        }
+    }
+
+    fn gid(&self) -> Option<u32> {
+        match self {
+            DirEntry::Dir(dir) => dir.get_gid(),
+            DirEntry::File(file) => file.get_gid(),
+        }
+    }
+
+    fn mode(&self) -> Option<u32> {
+        match self {
+            DirEntry::Dir(dir) => dir.get_mode(),
+            DirEntry::File(file) => file.get_mode(),
+        }
+    }
+
+    fn kind(&self) -> FileKind {
+        match self {
+            DirEntry::Dir(_) => FileKind::Dir,
+            DirEntry::File(file) => file.get_kind(),
+        }
+    }
+
+    /// Absolute path of the entry on disk.
+    pub fn path(&self) -> PathBuf {
+        match self {
+            DirEntry::Dir(dir) => dir.path(),
+            DirEntry::File(file) => file.path(),
+        }
+    }
+}
+
+impl TreeWalk for DirEntry<'_> {
+    const N_COLS: usize = 12;
+    const COL_TITLES: &'static [&'static str] = &[
+        "Name",
+        "Size",
+        "Allocated",
+        "Files Size",
+        "Items",
+        "Last Change",
+        "Last Access",
+        "Status Change",
+        "User",
+        "Group",
+        "Mode",
+        "Type",
+    ];
 
     fn children(&self) -> impl Iterator<Item = Self> {
-        self.get_dirs().iter()
+        let (dirs, files) = match *self {
+            DirEntry::Dir(dir) => (Some(dir.get_dirs()), Some(dir.get_files())),
+            DirEntry::File(_) => (None, None),
+        };
+        dirs.into_iter()
+            .flatten()
+            .map(DirEntry::Dir)
+            .chain(files.into_iter().flatten().map(DirEntry::File))
+    }
+
+    fn child(&self, idx: usize) -> Option<Self> {
+        let DirEntry::Dir(dir) = *self else {
+            return None;
+        };
+        // Subdirectories come first, so files are past the end of `dirs`
+        let dirs = dir.get_dirs();
+        match idx.checked_sub(dirs.len()) {
+            None => dirs.get(idx).map(DirEntry::Dir),
+            Some(idx) => dir.get_files().get(idx).map(DirEntry::File),
+        }
     }
 
     fn to_cols(&self) -> Vec<String> {
+        let items = match self {
+            DirEntry::Dir(_) => self.item_count().to_string(),
+            DirEntry::File(_) => String::new(),
+        };
         vec![
-            self.get_name().to_string(),
-            self.get_size().total_size.to_string(),
-            self.get_size().files_size.to_string(),
+            self.name().to_string(),
+            self.total_size().to_string(),
+            self.allocated().to_string(),
+            self.files_size().to_string(),
+            items,
+            self.modified().map(format_time).unwrap_or_default(),
+            // Only filled when collected, see `Config::metadata`
+            self.accessed().map(format_time).unwrap_or_default(),
+            self.changed().map(format_time).unwrap_or_default(),
+            self.uid().map(|uid| uid.to_string()).unwrap_or_default(),
+            self.gid().map(|gid| gid.to_string()).unwrap_or_default(),
+            self.mode()
+                .map(|mode| format!("{:04o}", mode))
+                .unwrap_or_default(),
+            kind_name(self.kind()).to_string(),
         ]
     }
-}
\ No newline at end of file
+
+    fn cmp_col(&self, other: &Self, col: usize) -> Ordering {
+        match col {
+            0 => self.name().cmp(other.name()),
+            1 => self.total_size().cmp(&other.total_size()),
+            2 => self.allocated().cmp(&other.allocated()),
+            3 => self.files_size().cmp(&other.files_size()),
+            4 => self.item_count().cmp(&other.item_count()),
+            5 => self.modified().cmp(&other.modified()),
+            6 => self.accessed().cmp(&other.accessed()),
+            7 => self.changed().cmp(&other.changed()),
+            8 => self.uid().cmp(&other.uid()),
+            9 => self.gid().cmp(&other.gid()),
+            10 => self.mode().cmp(&other.mode()),
+            11 => kind_name(self.kind()).cmp(kind_name(other.kind())),
+            _ => Ordering::Equal,
+        }
+    }
+
+    fn dimmed(&self) -> bool {
+        match self {
+            DirEntry::Dir(dir) => dir.is_ignored(),
+            DirEntry::File(file) => file.is_ignored(),
+        }
+    }
+
+    fn holds_dimmed(&self) -> bool {
+        match self {
+            DirEntry::Dir(dir) => dir.holds_ignored(),
+            DirEntry::File(file) => file.is_ignored(),
+        }
+    }
+}
+
+/// Columns of the tree view worth showing, as indices into
+/// `DirEntry::COL_TITLES`: those of metadata only when it is collected.
+pub fn columns(metadata: &MetadataFields) -> Vec<usize> {
+    // In the order of the last columns
+    let collected = [
+        metadata.accessed,
+        metadata.changed,
+        metadata.uid,
+        metadata.gid,
+        metadata.mode,
+        metadata.file_type,
+    ];
+    let first_metadata = DirEntry::N_COLS - collected.len();
+    (0..first_metadata)
+        .chain(
+            (first_metadata..DirEntry::N_COLS)
+                .zip(collected)
+                .filter(|(_, collected)| *collected)
+                .map(|(col, _)| col),
+        )
+        .collect()
+}
+
+/// The entry at `path` in the tree view, as indices into the children
+/// starting from `root`.
+pub fn entry_at<'a>(root: DirRef<'a>, path: &[usize]) -> Option<DirEntry<'a>> {
+    path.iter().try_fold(DirEntry::Dir(root), |entry, &idx| entry.child(idx))
+}
+
+/// The directory at `path` in the tree view, if it is a summarised one whose
+/// children can be loaded.
+pub fn summarized_dir<'a>(root: DirRef<'a>, path: &[usize]) -> Option<DirRef<'a>> {
+    match entry_at(root, path)? {
+        DirEntry::Dir(dir) if dir.is_summarized() => Some(dir),
+        _ => None,
+    }
+}
+
+fn kind_name(kind: FileKind) -> &'static str {
+    match kind {
+        FileKind::File => "File",
+        FileKind::Dir => "Directory",
+        FileKind::Symlink => "Symlink",
+        FileKind::Fifo => "FIFO",
+        FileKind::Socket => "Socket",
+        FileKind::BlockDevice => "Block device",
+        FileKind::CharDevice => "Char device",
+        FileKind::Other => "Other",
+    }
+}
+
+/// Formats a timestamp as `YYYY-MM-DD HH:MM` in UTC.
+fn format_time(time: SystemTime) -> String {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        // Rounded down, so that a fraction of a second before a minute
+        // isn't shown as that minute
+        Err(before) => {
+            let before = before.duration();
+            -(before.as_secs() as i64) - i64::from(before.subsec_nanos() > 0)
+        }
+    };
+    let (days, day_secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // Civil date from days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        day_secs / 3600,
+        day_secs % 3600 / 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn at(secs: i64) -> SystemTime {
+        match u64::try_from(secs) {
+            Ok(secs) => UNIX_EPOCH + Duration::from_secs(secs),
+            Err(_) => UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()),
+        }
+    }
+
+    #[test]
+    fn formats_times_in_utc() {
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01 00:00");
+        assert_eq!(format_time(at(1709214300)), "2024-02-29 13:45");
+        assert_eq!(format_time(at(1709214300 + 86400)), "2024-03-01 13:45");
+        assert_eq!(format_time(at(-14182940)), "1969-07-20 20:17");
+        // 1900 wasn't a leap year
+        assert_eq!(format_time(at(-2203891200)), "1900-03-01 00:00");
+        assert_eq!(
+            format_time(UNIX_EPOCH - Duration::from_millis(500)),
+            "1969-12-31 23:59"
+        );
+    }
+}
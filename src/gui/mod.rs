@@ -1,52 +1,199 @@
-use std::{borrow::Cow, io, path::PathBuf, rc::Rc, sync::Arc};
+use std::{
+    borrow::Cow,
+    path::PathBuf,
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
 
 use iced::{
-    color, executor, widget::{column, container, scrollable, slider, text, vertical_slider, Button, Text}, Application, Command, Theme
+    executor, widget::{column, container, row, Button, Text}, Application, Command, Theme
 };
-use iced::{Element, Length, Sandbox, Settings};
+use iced::{Element, Length, Settings, Subscription};
 
 use crate::{
-    gui::treeview::TreeView,
-    parse_tree::{parallel::parse_tree, Config, Dir, FileError},
+    gui::{
+        dir_walk::{columns, entry_at, summarized_dir, DirEntry},
+        treemap::{CushionOptions, TreeMap},
+        treeview::{DimmedRows, TreePath, TreeView},
+    },
+    format::format_size,
+    parse_tree::{
+        self, CancelToken, Config, DirRef, FileError, GitFilter, Listing, NodeId, PartialTree,
+        Progress, ProgressSnapshot, SizeKind, Strategy, Tree,
+    },
 };
 
+mod treemap;
 mod treeview;
 mod dir_walk;
 
-pub fn main() -> iced::Result {
-    RustDirStat::run(Settings::default())
+pub fn main(path: Option<PathBuf>, config: Config, strategy: Strategy) -> iced::Result {
+    RustDirStat::run(Settings::with_flags(Flags {
+        path,
+        config,
+        strategy,
+    }))
+}
+
+/// Startup options, as given on the command line.
+struct Flags {
+    path: Option<PathBuf>,
+    config: Config,
+    strategy: Strategy,
 }
 
 #[derive(Debug, Clone)]
 enum Message {
     PickDir,
     DirPicked(Option<PathBuf>),
-    DirWalked(Result<(Dir, Vec<FileError>), FileError>),
+    DirWalked(Result<(Tree, Vec<FileError>), FileError>),
+    Select(TreePath),
+    ToggleSizeKind,
+    /// Shows every entry, then hides those ignored by git, then only shows
+    /// those
+    CycleGitFilter,
+    LoadSubtree,
+    /// The walk of a summarised directory of the tree with the given
+    /// `RustDirStat::tree_id`
+    SubtreeLoaded(u64, NodeId, Result<(Tree, Vec<FileError>), FileError>),
+    /// Copies the path of the selected entry to the clipboard
+    CopyPath,
+    /// Time to poll the progress of the running scan
+    Tick,
+    CancelScan,
 }
 
 #[derive(Debug)]
 enum Page {
     Landing,
     PickingDir,
-    Loading(PathBuf),
-    Displaying(Dir, Vec<FileError>),
+    Loading(Scan),
+    Displaying(Tree, Vec<FileError>),
+}
+
+/// A scan in progress.
+#[derive(Debug)]
+struct Scan {
+    path: PathBuf,
+    progress: Arc<Progress>,
+    cancel: CancelToken,
+    started: Instant,
+    // Last polled state of `progress`
+    snapshot: ProgressSnapshot,
+    // Directories read by the walker, not yet added to `partial`
+    listings: mpsc::Receiver<Listing>,
+    // What was found so far, shown until the whole tree is ready
+    partial: PartialTree,
+    // Bumped whenever `partial` grows
+    revision: u64,
 }
 
+/// How often the progress of a scan is polled.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
 struct RustDirStat {
     page: Page,
+    config: Config,
+    // Walker of every scan, including those of summarised directories
+    strategy: Strategy,
+    // Columns of the tree view, see `dir_walk::columns`
+    columns: Vec<usize>,
+    // Entry selected in both the tree view and the treemap
+    selection: Option<TreePath>,
+    // Size the treemap areas are proportional to
+    size_kind: SizeKind,
+    // Entries shown of those ignored by git and the others
+    git_filter: GitFilter,
+    // Bumped whenever the displayed tree is changed in place
+    tree_revision: u64,
+    // Bumped whenever a different tree is displayed
+    tree_id: u64,
+    // Summarised directory of the displayed tree being walked, at most one
+    // at a time
+    loading_subtree: Option<NodeId>,
+}
+
+impl RustDirStat {
+    /// Walks `path` in the background, showing its progress meanwhile.
+    fn start_scan(&mut self, path: PathBuf) -> Command<Message> {
+        let progress = Arc::new(Progress::new());
+        let cancel = CancelToken::new();
+        let (stream, listings) = mpsc::channel();
+        let walk = parse_tree::Scan::with_config(&path, self.config.clone())
+            .progress(progress.clone())
+            .cancel(cancel.clone())
+            .stream(stream)
+            .strategy(self.strategy);
+        self.selection = None;
+        self.tree_id += 1;
+        self.loading_subtree = None;
+        self.page = Page::Loading(Scan {
+            path: path.clone(),
+            progress,
+            cancel,
+            started: Instant::now(),
+            snapshot: ProgressSnapshot::default(),
+            listings,
+            partial: PartialTree::new(),
+            revision: 0,
+        });
+        Command::perform(walk.run_async(), Message::DirWalked)
+    }
+
+    /// The tree view next to the treemap, showing `dir`. `revision` changes
+    /// whenever `dir` is changed in place.
+    fn tree_panes<'a>(&'a self, dir: DirRef<'a>, revision: u64) -> Element<'a, Message> {
+        row![
+            TreeView::new(DirEntry::Dir(dir))
+                .tree_id(self.tree_id)
+                .revision(revision)
+                .columns(self.columns.clone())
+                .selected(self.selection.as_ref())
+                .on_select(Message::Select)
+                .dimmed_rows(match self.git_filter {
+                    GitFilter::All => DimmedRows::Shown,
+                    GitFilter::Tracked => DimmedRows::Hidden,
+                    GitFilter::Ignored => DimmedRows::Only,
+                }),
+            TreeMap::new(dir)
+                .tree_id(self.tree_id)
+                .size_kind(self.size_kind)
+                .git_filter(self.git_filter)
+                .revision(revision)
+                .cushion(CushionOptions::default())
+                .selected(self.selection.as_ref())
+                .on_select(Message::Select),
+        ]
+        .spacing(10)
+        .height(Length::Fill)
+        .into()
+    }
 }
 
 impl Application for RustDirStat {
     type Message = Message;
     type Executor = executor::Default;
     type Theme = Theme;
-    type Flags = ();
+    type Flags = Flags;
 
-    fn new(_flags: ()) -> (RustDirStat, iced::Command<Message>) {
-        (RustDirStat {
-            page: Page::Loading("Test".into())
-        },
-        Command::perform(run_parse_tree("/home/robot_rover/Projects".into()), Message::DirWalked))
+    fn new(flags: Flags) -> (RustDirStat, iced::Command<Message>) {
+        let mut app = RustDirStat {
+            page: Page::Landing,
+            size_kind: flags.config.size_kind,
+            git_filter: GitFilter::All,
+            tree_revision: 0,
+            tree_id: 0,
+            loading_subtree: None,
+            columns: columns(&flags.config.metadata),
+            config: flags.config,
+            strategy: flags.strategy,
+            selection: None,
+        };
+        let command = match flags.path {
+            Some(path) => app.start_scan(path),
+            None => Command::none(),
+        };
+        (app, command)
     }
 
     fn title(&self) -> String {
@@ -54,25 +201,45 @@ impl Application for RustDirStat {
     }
 
     fn update(&mut self, message: Message) -> Command<Message> {
-        match (&self.page, message) {
+        match (&mut self.page, message) {
             (Page::Landing | Page::Displaying(_, _), Message::PickDir) => {
                 self.page = Page::PickingDir;
                 Command::perform(pick_dir(), Message::DirPicked)
             }
             (Page::PickingDir, Message::DirPicked(option)) => {
                 if let Some(dir_path) = option {
-                    self.page = Page::Loading(dir_path.clone());
                     println!("Dir Picked: {:?}", &dir_path);
-                    Command::perform(run_parse_tree(dir_path), Message::DirWalked)
+                    self.start_scan(dir_path)
                 } else {
                     self.page = Page::Landing;
                     Command::none()
                 }
             }
+            (Page::Loading(scan), Message::Tick) => {
+                scan.snapshot = scan.progress.snapshot();
+                let mut grown = false;
+                while let Ok(listing) = scan.listings.try_recv() {
+                    scan.partial.add(listing);
+                    grown = true;
+                }
+                if grown {
+                    scan.revision += 1;
+                }
+                Command::none()
+            }
+            // The partial tree comes with the DirWalked message
+            (Page::Loading(scan), Message::CancelScan) => {
+                scan.cancel.cancel();
+                Command::none()
+            }
+            // Left over from a scan which just finished
+            (_, Message::Tick | Message::CancelScan) => Command::none(),
             (Page::Loading(_), Message::DirWalked(result)) => {
                 match result {
-                    Ok((dir, errors)) => {
-                        self.page = Page::Displaying(dir, errors);
+                    Ok((tree, errors)) => {
+                        self.selection = None;
+                        self.tree_id += 1;
+                        self.page = Page::Displaying(tree, errors);
                     }
                     Err(err) => {
                         eprintln!("Error walking directory: {:?}", err);
@@ -81,6 +248,77 @@ impl Application for RustDirStat {
                 }
                 Command::none()
             }
+            (Page::Loading(_) | Page::Displaying(_, _), Message::Select(path)) => {
+                self.selection = Some(path);
+                Command::none()
+            }
+            (_, Message::ToggleSizeKind) => {
+                self.size_kind = match self.size_kind {
+                    SizeKind::Apparent => SizeKind::Allocated,
+                    SizeKind::Allocated => SizeKind::Apparent,
+                };
+                Command::none()
+            }
+            (_, Message::CycleGitFilter) => {
+                self.git_filter = match self.git_filter {
+                    GitFilter::All => GitFilter::Tracked,
+                    GitFilter::Tracked => GitFilter::Ignored,
+                    GitFilter::Ignored => GitFilter::All,
+                };
+                Command::none()
+            }
+            (Page::Displaying(tree, _), Message::LoadSubtree) => {
+                let summarized = self
+                    .selection
+                    .as_ref()
+                    .and_then(|path| summarized_dir(tree.root(), path))
+                    .filter(|_| self.loading_subtree.is_none());
+                match summarized {
+                    Some(dir) => {
+                        let id = dir.id();
+                        let tree_id = self.tree_id;
+                        self.loading_subtree = Some(id);
+                        // Loaded in full, rather than one more level at a time
+                        let mut config = self.config.clone();
+                        config.max_depth = None;
+                        Command::perform(
+                            parse_tree::Scan::subtree(dir, config)
+                                .strategy(self.strategy)
+                                .run_async(),
+                            move |result| Message::SubtreeLoaded(tree_id, id, result),
+                        )
+                    }
+                    None => Command::none(),
+                }
+            }
+            (Page::Displaying(tree, errors), Message::SubtreeLoaded(tree_id, id, result))
+                if tree_id == self.tree_id =>
+            {
+                self.loading_subtree = None;
+                match result {
+                    Ok((subtree, mut subtree_errors)) => {
+                        tree.load_subtree(id, subtree);
+                        errors.append(&mut subtree_errors);
+                        self.tree_revision += 1;
+                    }
+                    Err(err) => errors.push(err),
+                }
+                Command::none()
+            }
+            // Left over from a tree which isn't displayed any more
+            (_, Message::SubtreeLoaded(..)) => Command::none(),
+            (Page::Displaying(tree, _), Message::CopyPath) => {
+                let selected = self
+                    .selection
+                    .as_ref()
+                    .and_then(|path| entry_at(tree.root(), path));
+                match selected {
+                    Some(entry) => {
+                        iced::clipboard::write(entry.path().to_string_lossy().into_owned())
+                    }
+                    None => Command::none(),
+                }
+            }
             (page, message) => {
                 eprintln!("Unhandled message: {:?} in page: {:?}", message, page);
                 self.page = Page::Landing;
@@ -89,30 +327,91 @@ impl Application for RustDirStat {
         }
     }
 
-    fn view(&self) -> Element<Message> {
+    fn subscription(&self) -> Subscription<Message> {
+        match self.page {
+            Page::Loading(_) => iced::time::every(PROGRESS_INTERVAL).map(|_| Message::Tick),
+            _ => Subscription::none(),
+        }
+    }
+
+    fn view(&self) -> Element<'_, Message> {
         let status_message: Cow<str> = match &self.page {
             Page::Landing => "Select a directory to scan...".into(),
             Page::PickingDir => "Picking directory...".into(),
-            Page::Loading(path) => format!("Reading Subtree of {}", path.display()).into(),
-            Page::Displaying(dir, errors) => format!(
+            Page::Loading(scan) => format!("Reading Subtree of {}", scan.path.display()).into(),
+            Page::Displaying(tree, errors) if tree.root().is_incomplete() => format!(
+                "Cancelled reading {}, showing what was found, with {} errors",
+                tree.root().get_name(),
+                errors.len()
+            )
+            .into(),
+            Page::Displaying(tree, errors) => format!(
                 "Finished reading {}, found {} errors",
-                dir.get_name(),
+                tree.root().get_name(),
                 errors.len()
             )
             .into(),
         };
         let display = Text::new(status_message);
-        let open_picker = Button::new("Open Folder").on_press(Message::PickDir);
-        let content = if let Page::Displaying(dir, errors) = &self.page {
+        let mut buttons = row![Button::new("Open Folder").on_press(Message::PickDir)].spacing(10);
+        if let Page::Displaying(tree, _) = &self.page {
+            let selected = self
+                .selection
+                .as_ref()
+                .and_then(|path| entry_at(tree.root(), path));
+            let summarized = self
+                .selection
+                .as_ref()
+                .and_then(|path| summarized_dir(tree.root(), path));
+            buttons = buttons.push(
+                Button::new("Copy Path").on_press_maybe(selected.map(|_| Message::CopyPath)),
+            );
+            let loading = self.loading_subtree.is_some();
+            buttons = buttons.push(
+                Button::new(if loading {
+                    "Loading Subtree..."
+                } else {
+                    "Load Subtree"
+                })
+                .on_press_maybe(summarized.filter(|_| !loading).map(|_| Message::LoadSubtree)),
+            );
+            buttons = buttons.push(
+                Button::new(match self.size_kind {
+                    SizeKind::Apparent => "Show Allocated Size",
+                    SizeKind::Allocated => "Show Apparent Size",
+                })
+                .on_press(Message::ToggleSizeKind),
+            );
+            if self.config.gitignore {
+                buttons = buttons.push(
+                    Button::new(match self.git_filter {
+                        GitFilter::All => "Hide Ignored",
+                        GitFilter::Tracked => "Only Ignored",
+                        GitFilter::Ignored => "Show All",
+                    })
+                    .on_press(Message::CycleGitFilter),
+                );
+            }
+        }
+        let content = if let Page::Displaying(tree, _errors) = &self.page {
             column![
                 container(display).center_x(),
-                container(TreeView::new(dir)).center_x(),
-                container(open_picker).center_x(),
+                self.tree_panes(tree.root(), self.tree_revision),
+                container(buttons).center_x(),
             ]
+        } else if let Page::Loading(scan) = &self.page {
+            let mut content = column![
+                container(display).center_x(),
+                container(progress_view(scan)).center_x(),
+            ];
+            if let Some(partial) = scan.partial.root() {
+                content = content.push(self.tree_panes(partial, scan.revision));
+            }
+            content.push(container(Button::new("Cancel").on_press(Message::CancelScan)).center_x())
         } else {
             column![
                 container(display).center_x(),
-                container(open_picker).center_x(),
+                container(buttons).center_x(),
             ]
         };
         let el: Element<_> = container(content.spacing(25).padding(25))
@@ -134,10 +433,68 @@ async fn pick_dir() -> Option<PathBuf> {
     path
 }
 
-async fn run_parse_tree(path: PathBuf) -> Result<(Dir, Vec<FileError>), FileError> {
-    let config = Config {
-        same_filesystem: true,
-        follow_symlinks: false,
-    };
-    parse_tree(path.clone(), config)
+fn progress_view(scan: &Scan) -> Element<'_, Message> {
+    let snapshot = &scan.snapshot;
+    let elapsed = scan.started.elapsed().as_secs_f64();
+    let per_second = |count: u64| if elapsed > 0.0 { count as f64 / elapsed } else { 0.0 };
+    column![
+        Text::new(format!(
+            "{} entries, {}, {} errors",
+            snapshot.entries,
+            format_size(snapshot.bytes),
+            snapshot.errors
+        )),
+        Text::new(format!(
+            "{:.0} entries/s, {}/s, {:.1} s elapsed",
+            per_second(snapshot.entries),
+            format_size(per_second(snapshot.bytes) as u64),
+            elapsed
+        )),
+        Text::new(format!("In {}", snapshot.current_dir.display())).size(12),
+    ]
+    .spacing(5)
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn late_cancel_keeps_tree() {
+        let path = std::env::temp_dir().join(format!("rustdirstat-gui-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(path.join("dir")).unwrap();
+        fs::write(path.join("dir/file"), "contents").unwrap();
+
+        let (mut app, _) = RustDirStat::new(Flags {
+            path: None,
+            config: Config::new(false, true),
+            strategy: Strategy::Serial,
+        });
+        // The walk itself is run here rather than by iced
+        let _ = app.start_scan(path.clone());
+        let Page::Loading(scan) = &app.page else {
+            panic!("the scan didn't start");
+        };
+        let cancel = scan.cancel.clone();
+        let result = parse_tree::Scan::new(&path)
+            .cancel(cancel.clone())
+            .strategy(Strategy::Serial)
+            .run();
+        let _ = app.update(Message::DirWalked(result));
+        // Clicked just as the scan finished
+        cancel.cancel();
+        let _ = app.update(Message::CancelScan);
+
+        let Page::Displaying(tree, _) = &app.page else {
+            panic!("the tree was dropped");
+        };
+        assert!(!tree.root().is_incomplete());
+        assert_eq!(tree.root().get_size().item_count, 2);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
 }
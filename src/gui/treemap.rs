@@ -0,0 +1,685 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    path::Path,
+};
+
+use iced::{
+    advanced::{
+        image::{self, FilterMethod},
+        layout, mouse,
+        renderer::{self, Quad},
+        widget::tree,
+        Clipboard, Shell, Widget,
+    },
+    event, Border, Color, Element, Event, Length, Rectangle, Size, Vector,
+};
+
+use crate::parse_tree::{DirRef, FileRef, GitFilter, SizeKind};
+
+use super::treeview::TreePath;
+
+// Same palette WinDirStat uses by default
+const PALETTE: [Color; 13] = [
+    Color::from_rgb(0.0, 0.0, 1.0),
+    Color::from_rgb(1.0, 0.0, 0.0),
+    Color::from_rgb(0.0, 1.0, 0.0),
+    Color::from_rgb(0.0, 1.0, 1.0),
+    Color::from_rgb(1.0, 0.0, 1.0),
+    Color::from_rgb(1.0, 1.0, 0.0),
+    Color::from_rgb(0.59, 0.59, 1.0),
+    Color::from_rgb(1.0, 0.59, 0.59),
+    Color::from_rgb(0.59, 1.0, 0.59),
+    Color::from_rgb(0.59, 1.0, 1.0),
+    Color::from_rgb(1.0, 0.59, 1.0),
+    Color::from_rgb(1.0, 1.0, 0.59),
+    Color::from_rgb(1.0, 1.0, 1.0),
+];
+const DIR_COLOR: Color = Color::from_rgb(0.5, 0.5, 0.5);
+const BORDER_COLOR: Color = Color::from_rgb(0.2, 0.2, 0.2);
+const SELECTED_COLOR: Color = Color::WHITE;
+
+/// Parameters of the cushion shading, named after their WinDirStat
+/// counterparts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CushionOptions {
+    /// Height of the ridge added for the outermost rectangles.
+    pub height: f64,
+    /// Factor the ridge height is multiplied by at each nesting level.
+    pub scale_factor: f64,
+    /// Share of the brightness which does not depend on the light source.
+    pub ambient_light: f64,
+    /// Horizontal direction the light comes from, -1 is the left edge.
+    pub light_x: f64,
+    /// Vertical direction the light comes from, -1 is the top edge.
+    pub light_y: f64,
+}
+
+impl Default for CushionOptions {
+    fn default() -> Self {
+        CushionOptions {
+            height: 0.38,
+            scale_factor: 0.91,
+            ambient_light: 0.13,
+            light_x: -1.0,
+            light_y: -1.0,
+        }
+    }
+}
+
+pub struct TreeMap<'a, Message> {
+    root: DirRef<'a>,
+    size_kind: SizeKind,
+    git_filter: GitFilter,
+    tree_id: u64,
+    revision: u64,
+    cushion: Option<CushionOptions>,
+    selected: Option<&'a TreePath>,
+    on_select: Option<Box<dyn Fn(TreePath) -> Message + 'a>>,
+}
+
+impl<'a, Message> TreeMap<'a, Message> {
+    pub fn new(root: DirRef<'a>) -> Self {
+        TreeMap {
+            root,
+            size_kind: SizeKind::default(),
+            git_filter: GitFilter::All,
+            tree_id: 0,
+            revision: 0,
+            cushion: None,
+            selected: None,
+            on_select: None,
+        }
+    }
+
+    /// Size used as the area of each rectangle.
+    pub fn size_kind(mut self, size_kind: SizeKind) -> Self {
+        self.size_kind = size_kind;
+        self
+    }
+
+    /// Entries shown, telling apart those ignored by git. They are greyed
+    /// out when every entry is shown.
+    pub fn git_filter(mut self, git_filter: GitFilter) -> Self {
+        self.git_filter = git_filter;
+        self
+    }
+
+    /// Identifies the tree shown, to lay it out again when another one is
+    /// shown.
+    pub fn tree_id(mut self, tree_id: u64) -> Self {
+        self.tree_id = tree_id;
+        self
+    }
+
+    /// Changes whenever the tree is modified in place, to lay it out again.
+    pub fn revision(mut self, revision: u64) -> Self {
+        self.revision = revision;
+        self
+    }
+
+    pub fn cushion(mut self, options: CushionOptions) -> Self {
+        self.cushion = Some(options);
+        self
+    }
+
+    /// Frames the rectangle of the entry at `path`, using the same paths as
+    /// the tree view rows.
+    pub fn selected(mut self, path: Option<&'a TreePath>) -> Self {
+        self.selected = path;
+        self
+    }
+
+    pub fn on_select(mut self, on_select: impl Fn(TreePath) -> Message + 'a) -> Self {
+        self.on_select = Some(Box::new(on_select));
+        self
+    }
+}
+
+/// Coefficients of the cushion surface `z = a*x^2 + b*y^2 + c*x + d*y` over
+/// a rectangle, stored as `[a, b, c, d]`.
+type Surface = [f64; 4];
+
+#[derive(Debug, Clone)]
+struct Cell {
+    bounds: Rectangle,
+    color: Color,
+    surface: Surface,
+    path: TreePath,
+}
+
+#[derive(Debug)]
+pub struct TreeMapState {
+    tree_id: u64,
+    size: Size,
+    size_kind: SizeKind,
+    git_filter: GitFilter,
+    revision: u64,
+    cushion: Option<CushionOptions>,
+    cells: Vec<Cell>,
+    bitmap: Option<image::Handle>,
+    selected: Option<TreePath>,
+    highlight: Option<Rectangle>,
+}
+
+impl<Message, Theme, Renderer> Widget<Message, Theme, Renderer> for TreeMap<'_, Message>
+where
+    Renderer: renderer::Renderer + image::Renderer<Handle = image::Handle>,
+{
+    fn tag(&self) -> tree::Tag {
+        tree::Tag::of::<TreeMapState>()
+    }
+
+    fn state(&self) -> tree::State {
+        tree::State::new(TreeMapState {
+            tree_id: self.tree_id,
+            size: Size::ZERO,
+            size_kind: self.size_kind,
+            git_filter: self.git_filter,
+            revision: self.revision,
+            cushion: None,
+            cells: Vec::new(),
+            bitmap: None,
+            selected: None,
+            highlight: None,
+        })
+    }
+
+    fn size(&self) -> Size<Length> {
+        Size {
+            width: Length::Fill,
+            height: Length::Fill,
+        }
+    }
+
+    fn layout(
+        &self,
+        tree: &mut tree::Tree,
+        _renderer: &Renderer,
+        limits: &layout::Limits,
+    ) -> layout::Node {
+        let node = layout::atomic(limits, Length::Fill, Length::Fill);
+        let state = tree.state.downcast_mut::<TreeMapState>();
+
+        let relayout = state.tree_id != self.tree_id
+            || state.size != node.size()
+            || state.size_kind != self.size_kind
+            || state.git_filter != self.git_filter
+            || state.revision != self.revision
+            || state.cushion != self.cushion;
+        if relayout {
+            state.tree_id = self.tree_id;
+            state.size = node.size();
+            state.size_kind = self.size_kind;
+            state.git_filter = self.git_filter;
+            state.revision = self.revision;
+            state.cushion = self.cushion;
+            state.cells.clear();
+
+            let bounds = Rectangle::with_size(node.size());
+            let mut surface = Surface::default();
+            let height = match &self.cushion {
+                Some(options) => {
+                    add_ridge(bounds, &mut surface, options.height);
+                    options.height * options.scale_factor
+                }
+                None => 0.0,
+            };
+            let scale_factor = self.cushion.map_or(0.0, |options| options.scale_factor);
+            CellLayout {
+                size_kind: self.size_kind,
+                git_filter: self.git_filter,
+                scale_factor,
+                path: TreePath::new(),
+                cells: &mut state.cells,
+            }
+            .layout_dir(self.root, bounds, surface, height);
+
+            state.bitmap = self
+                .cushion
+                .map(|options| render_cushions(&state.cells, node.size(), &options));
+        }
+
+        if relayout || self.selected != state.selected.as_ref() {
+            state.selected = self.selected.cloned();
+            // A directory covers exactly the cells of its descendants
+            state.highlight = self.selected.and_then(|selected| {
+                state
+                    .cells
+                    .iter()
+                    .filter(|cell| cell.path.starts_with(selected))
+                    .map(|cell| cell.bounds)
+                    .reduce(|lhs, rhs| lhs.union(&rhs))
+            });
+        }
+
+        node
+    }
+
+    fn draw(
+        &self,
+        tree: &tree::Tree,
+        renderer: &mut Renderer,
+        _theme: &Theme,
+        _style: &renderer::Style,
+        layout: layout::Layout<'_>,
+        _cursor: iced::advanced::mouse::Cursor,
+        _viewport: &Rectangle,
+    ) {
+        let state = tree.state.downcast_ref::<TreeMapState>();
+        let bounds = layout.bounds();
+        if let Some(bitmap) = &state.bitmap {
+            image::Renderer::draw(renderer, bitmap.clone(), FilterMethod::Nearest, bounds);
+        }
+        renderer.with_layer(bounds, |renderer| {
+            renderer.with_translation(Vector::new(bounds.x, bounds.y), |renderer| {
+                let flat_cells = if state.bitmap.is_some() {
+                    &[][..]
+                } else {
+                    &state.cells[..]
+                };
+                for cell in flat_cells {
+                    // Borders would swallow rectangles only a few pixels wide
+                    let border_width = if cell.bounds.width.min(cell.bounds.height) > 3.0 {
+                        0.5
+                    } else {
+                        0.0
+                    };
+                    renderer.fill_quad(
+                        Quad {
+                            bounds: cell.bounds,
+                            border: Border {
+                                color: BORDER_COLOR,
+                                width: border_width,
+                                radius: 0.0.into(),
+                            },
+                            ..Quad::default()
+                        },
+                        cell.color,
+                    );
+                }
+
+                if let Some(highlight) = state.highlight {
+                    renderer.fill_quad(
+                        Quad {
+                            bounds: highlight,
+                            border: Border {
+                                color: SELECTED_COLOR,
+                                width: 2.0,
+                                radius: 0.0.into(),
+                            },
+                            ..Quad::default()
+                        },
+                        Color::TRANSPARENT,
+                    );
+                }
+            });
+        });
+    }
+
+    fn on_event(
+        &mut self,
+        tree: &mut tree::Tree,
+        event: Event,
+        layout: layout::Layout<'_>,
+        cursor: mouse::Cursor,
+        _renderer: &Renderer,
+        _clipboard: &mut dyn Clipboard,
+        shell: &mut Shell<'_, Message>,
+        _viewport: &Rectangle,
+    ) -> event::Status {
+        let Event::Mouse(mouse::Event::ButtonPressed(mouse::Button::Left)) = event else {
+            return event::Status::Ignored;
+        };
+        let Some(position) = cursor.position_in(layout.bounds()) else {
+            return event::Status::Ignored;
+        };
+        let state = tree.state.downcast_ref::<TreeMapState>();
+        let clicked = state
+            .cells
+            .iter()
+            .find(|cell| cell.bounds.contains(position));
+        if let (Some(cell), Some(on_select)) = (clicked, &self.on_select) {
+            shell.publish(on_select(cell.path.clone()));
+        }
+        event::Status::Captured
+    }
+}
+
+impl<'a, Message, Theme, Renderer> From<TreeMap<'a, Message>>
+    for Element<'a, Message, Theme, Renderer>
+where
+    Renderer: renderer::Renderer + image::Renderer<Handle = image::Handle>,
+    Message: 'a,
+{
+    fn from(tree_map: TreeMap<'a, Message>) -> Self {
+        Self::new(tree_map)
+    }
+}
+
+enum Child<'a> {
+    Dir(DirRef<'a>),
+    File(FileRef<'a>),
+}
+
+impl Child<'_> {
+    fn is_ignored(&self) -> bool {
+        match self {
+            Child::Dir(d) => d.is_ignored(),
+            Child::File(f) => f.is_ignored(),
+        }
+    }
+
+    fn is_kept(&self, git_filter: GitFilter) -> bool {
+        match self {
+            Child::Dir(d) => git_filter.keeps_dir(*d),
+            Child::File(f) => git_filter.keeps_file(*f),
+        }
+    }
+}
+
+/// Settings shared by the whole recursive layout, and its output.
+struct CellLayout<'c> {
+    size_kind: SizeKind,
+    git_filter: GitFilter,
+    scale_factor: f64,
+    /// Path of the directory being laid out
+    path: TreePath,
+    cells: &'c mut Vec<Cell>,
+}
+
+impl CellLayout<'_> {
+    /// Lays out the children of `dir` inside `bounds`. `surface` already contains
+    /// the ridges of `dir` and its ancestors, and `height` is the ridge height
+    /// for the children.
+    fn layout_dir(&mut self, dir: DirRef, bounds: Rectangle, surface: Surface, height: f64) {
+        let (size_kind, git_filter) = (self.size_kind, self.git_filter);
+        // Index children like the tree view does: subdirectories, then files
+        let mut children: Vec<(u64, usize, Child)> = dir
+            .get_dirs()
+            .map(|d| (git_filter.dir_size(d, size_kind), Child::Dir(d)))
+            .chain(
+                dir.get_files()
+                    .map(|f| (f.get_size_as(size_kind), Child::File(f))),
+            )
+            .enumerate()
+            .map(|(idx, (size, child))| (size, idx, child))
+            .filter(|(size, _, child)| *size > 0 && child.is_kept(git_filter))
+            .collect();
+        children.sort_unstable_by_key(|(size, _, _)| std::cmp::Reverse(*size));
+
+        let weights: Vec<u64> = children.iter().map(|(size, _, _)| *size).collect();
+        for (rect, (_, idx, child)) in squarify(&weights, bounds).into_iter().zip(&children) {
+            let mut child_surface = surface;
+            add_ridge(rect, &mut child_surface, height);
+            self.path.push(*idx);
+            let color = |base| {
+                if git_filter == GitFilter::All && child.is_ignored() {
+                    ignored_color(base)
+                } else {
+                    base
+                }
+            };
+            match child {
+                // Too small to show anything inside, or nothing to show, so
+                // draw it as a single block
+                Child::Dir(d) if rect.width < 1.0 || rect.height < 1.0 || d.is_summarized() => {
+                    self.cells.push(Cell {
+                        bounds: rect,
+                        color: color(DIR_COLOR),
+                        surface: child_surface,
+                        path: self.path.clone(),
+                    })
+                }
+                Child::Dir(d) => {
+                    self.layout_dir(*d, rect, child_surface, height * self.scale_factor)
+                }
+                Child::File(f) => self.cells.push(Cell {
+                    bounds: rect,
+                    color: color(file_color(*f)),
+                    surface: child_surface,
+                    path: self.path.clone(),
+                }),
+            }
+            self.path.pop();
+        }
+    }
+}
+
+fn file_color(file: FileRef) -> Color {
+    let Some(ext) = Path::new(file.get_name()).extension() else {
+        return PALETTE[PALETTE.len() - 1];
+    };
+    let mut hasher = DefaultHasher::new();
+    ext.to_ascii_lowercase().hash(&mut hasher);
+    PALETTE[hasher.finish() as usize % (PALETTE.len() - 1)]
+}
+
+/// Washes out the colour of an entry ignored by git.
+fn ignored_color(color: Color) -> Color {
+    let mix = |c: f32| c * 0.35 + 0.85 * 0.65;
+    Color::from_rgb(mix(color.r), mix(color.g), mix(color.b))
+}
+
+/// Adds a parabolic ridge of the given height spanning `bounds` in both
+/// directions, as described by van Wijk & van de Wetering (Cushion Treemaps).
+fn add_ridge(bounds: Rectangle, surface: &mut Surface, height: f64) {
+    if height == 0.0 {
+        return;
+    }
+    let (left, right) = (bounds.x as f64, (bounds.x + bounds.width) as f64);
+    let (top, bottom) = (bounds.y as f64, (bounds.y + bounds.height) as f64);
+    if right > left {
+        let factor = 4.0 * height / (right - left);
+        surface[0] -= factor;
+        surface[2] += factor * (right + left);
+    }
+    if bottom > top {
+        let factor = 4.0 * height / (bottom - top);
+        surface[1] -= factor;
+        surface[3] += factor * (bottom + top);
+    }
+}
+
+fn render_cushions(cells: &[Cell], size: Size, options: &CushionOptions) -> image::Handle {
+    let width = size.width.max(0.0) as usize;
+    let height = size.height.max(0.0) as usize;
+    let mut pixels = vec![0u8; width * height * 4];
+
+    // The light source sits well above the treemap, like in WinDirStat
+    let light_z = 10.0;
+    let light_len = (options.light_x.powi(2) + options.light_y.powi(2) + light_z * light_z).sqrt();
+    let (lx, ly, lz) = (
+        options.light_x / light_len,
+        options.light_y / light_len,
+        light_z / light_len,
+    );
+    let diffuse = 1.0 - options.ambient_light;
+
+    for cell in cells {
+        let x_range = cell.bounds.x.round().max(0.0) as usize
+            ..((cell.bounds.x + cell.bounds.width).round() as usize).min(width);
+        let y_range = cell.bounds.y.round().max(0.0) as usize
+            ..((cell.bounds.y + cell.bounds.height).round() as usize).min(height);
+        let [a, b, c, d] = cell.surface;
+        for y in y_range {
+            let ny = -(2.0 * b * (y as f64 + 0.5) + d);
+            for x in x_range.clone() {
+                let nx = -(2.0 * a * (x as f64 + 0.5) + c);
+                let cos = (nx * lx + ny * ly + lz) / (nx * nx + ny * ny + 1.0).sqrt();
+                let brightness = (options.ambient_light + diffuse * cos.max(0.0)) as f32;
+
+                let idx = (y * width + x) * 4;
+                pixels[idx..idx + 4].copy_from_slice(&[
+                    (cell.color.r * brightness * 255.0).min(255.0) as u8,
+                    (cell.color.g * brightness * 255.0).min(255.0) as u8,
+                    (cell.color.b * brightness * 255.0).min(255.0) as u8,
+                    255,
+                ]);
+            }
+        }
+    }
+
+    image::Handle::from_pixels(width as u32, height as u32, pixels)
+}
+
+/// Squarified treemap layout (Bruls, Huizing & van Wijk). `weights` must be
+/// sorted in descending order, and one rectangle is returned for each weight.
+fn squarify(weights: &[u64], bounds: Rectangle) -> Vec<Rectangle> {
+    let total: u64 = weights.iter().sum();
+    let mut rects = Vec::with_capacity(weights.len());
+    if total == 0 || bounds.width <= 0.0 || bounds.height <= 0.0 {
+        rects.resize(weights.len(), Rectangle::new(bounds.position(), Size::ZERO));
+        return rects;
+    }
+
+    let scale = bounds.width as f64 * bounds.height as f64 / total as f64;
+    let areas: Vec<f64> = weights.iter().map(|&w| w as f64 * scale).collect();
+
+    let mut remaining = bounds;
+    let mut start = 0;
+    while start < areas.len() {
+        let side = remaining.width.min(remaining.height) as f64;
+
+        // Grow the row for as long as it makes the worst aspect ratio better
+        let mut end = start + 1;
+        let mut row_area = areas[start];
+        let mut row_worst = worst_ratio(areas[start], areas[start], row_area, side);
+        while end < areas.len() {
+            let next_area = row_area + areas[end];
+            let next_worst = worst_ratio(areas[start], areas[end], next_area, side);
+            if next_worst > row_worst {
+                break;
+            }
+            row_area = next_area;
+            row_worst = next_worst;
+            end += 1;
+        }
+
+        let thickness = if side > 0.0 { row_area / side } else { 0.0 };
+        let mut offset = 0.0;
+        for &area in &areas[start..end] {
+            let length = if thickness > 0.0 {
+                area / thickness
+            } else {
+                0.0
+            };
+            rects.push(if remaining.width >= remaining.height {
+                Rectangle {
+                    x: remaining.x,
+                    y: remaining.y + offset as f32,
+                    width: thickness as f32,
+                    height: length as f32,
+                }
+            } else {
+                Rectangle {
+                    x: remaining.x + offset as f32,
+                    y: remaining.y,
+                    width: length as f32,
+                    height: thickness as f32,
+                }
+            });
+            offset += length;
+        }
+
+        if remaining.width >= remaining.height {
+            remaining.x += thickness as f32;
+            remaining.width = (remaining.width - thickness as f32).max(0.0);
+        } else {
+            remaining.y += thickness as f32;
+            remaining.height = (remaining.height - thickness as f32).max(0.0);
+        }
+        start = end;
+    }
+
+    rects
+}
+
+fn worst_ratio(largest: f64, smallest: f64, row_area: f64, side: f64) -> f64 {
+    let side_sq = side * side;
+    let area_sq = row_area * row_area;
+    (side_sq * largest / area_sq).max(area_sq / (side_sq * smallest))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::parse_tree::Scan;
+
+    fn area(rect: &Rectangle) -> f64 {
+        rect.width as f64 * rect.height as f64
+    }
+
+    #[test]
+    fn squarify_tiles_bounds_in_proportion() {
+        let bounds = Rectangle::new(iced::Point::new(10.0, 20.0), Size::new(100.0, 60.0));
+        let weights = [600, 300, 60, 30, 10];
+        let rects = squarify(&weights, bounds);
+        assert_eq!(rects.len(), weights.len());
+
+        let scale = area(&bounds) / weights.iter().sum::<u64>() as f64;
+        for (rect, &weight) in rects.iter().zip(&weights) {
+            assert!((area(rect) - weight as f64 * scale).abs() < 0.01, "{:?}", rect);
+            let inside = rect.x >= bounds.x - 0.001
+                && rect.y >= bounds.y - 0.001
+                && rect.x + rect.width <= bounds.x + bounds.width + 0.001
+                && rect.y + rect.height <= bounds.y + bounds.height + 0.001;
+            assert!(inside, "{:?}", rect);
+        }
+        for (i, lhs) in rects.iter().enumerate() {
+            for rhs in &rects[i + 1..] {
+                let overlap = lhs.intersection(rhs).map_or(0.0, |rect| area(&rect));
+                assert!(overlap < 0.01, "{:?} overlaps {:?}", lhs, rhs);
+            }
+        }
+        let covered: f64 = rects.iter().map(area).sum();
+        assert!((covered - area(&bounds)).abs() < 0.01);
+    }
+
+    #[test]
+    fn squarify_gives_nothing_to_zero_weights() {
+        let bounds = Rectangle::with_size(Size::new(40.0, 30.0));
+        let rects = squarify(&[5, 3, 0, 0], bounds);
+        assert_eq!(rects.len(), 4);
+        assert!(rects[2..].iter().all(|rect| area(rect) == 0.0));
+        let rects = squarify(&[0, 0], bounds);
+        assert!(rects.iter().all(|rect| area(rect) == 0.0));
+    }
+
+    #[test]
+    fn empty_entries_get_no_cell() {
+        let path =
+            std::env::temp_dir().join(format!("rustdirstat-treemap-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(path.join("empty_dir")).unwrap();
+        fs::write(path.join("big"), [0; 300]).unwrap();
+        fs::write(path.join("small"), [0; 100]).unwrap();
+        fs::write(path.join("empty"), []).unwrap();
+        let (tree, _) = Scan::new(&path).run().unwrap();
+
+        let mut cells = Vec::new();
+        let bounds = Rectangle::with_size(Size::new(80.0, 50.0));
+        CellLayout {
+            size_kind: SizeKind::Apparent,
+            git_filter: GitFilter::All,
+            scale_factor: 0.0,
+            path: TreePath::new(),
+            cells: &mut cells,
+        }
+        .layout_dir(tree.root(), bounds, Surface::default(), 0.0);
+
+        // Children are indexed subdirectories first, and the only one is empty
+        let mut names: Vec<_> = cells
+            .iter()
+            .map(|cell| tree.root().get_files().get(cell.path[0] - 1).unwrap().get_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["big", "small"]);
+        let (big, small) = (area(&cells[0].bounds), area(&cells[1].bounds));
+        assert!((big - 3.0 * small).abs() < 0.01);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
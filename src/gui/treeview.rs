@@ -1,35 +1,94 @@
-use std::iter;
+use std::{cmp::Ordering, collections::HashMap, iter, mem, ops::Range};
 
 use iced::{
     advanced::{
-        graphics::text::cosmic_text::Align, layout, renderer, text::{self, Paragraph}, widget::tree, Text, Widget
-    }, alignment, widget::text::{LineHeight, Shaping}, Alignment, Color, Element, Length, Point, Rectangle, Size
+        layout, mouse, renderer::Quad, text::{self, Paragraph}, widget::tree, Clipboard, Shell, Text, Widget
+    }, alignment, event, widget::text::{LineHeight, Shaping}, Border, Color, Element, Event, Length, Point, Rectangle, Size, Vector
 };
 
+const SELECTED_COLOR: Color = Color::from_rgb(0.75, 0.85, 1.0);
+const DISCLOSURE_COLOR: Color = Color::from_rgb(0.4, 0.4, 0.4);
+const DIMMED_COLOR: Color = Color::from_rgb(0.55, 0.55, 0.55);
+// Horizontal space taken by each level of nesting, which also holds the
+// disclosure box of the row
+const INDENT: f32 = 16.0;
+const DISCLOSURE_SIZE: f32 = 9.0;
+const SCROLLBAR_WIDTH: f32 = 10.0;
+const SCROLLBAR_MIN_THUMB: f32 = 20.0;
+const SCROLLBAR_COLOR: Color = Color::from_rgb(0.9, 0.9, 0.9);
+const SCROLLBAR_THUMB_COLOR: Color = Color::from_rgb(0.6, 0.6, 0.6);
+// Rows moved by one notch of the mouse wheel
+const WHEEL_ROWS: f32 = 3.0;
+const HEADER_COLOR: Color = Color::from_rgb(0.92, 0.92, 0.92);
+const SEPARATOR_COLOR: Color = Color::from_rgb(0.7, 0.7, 0.7);
+const MIN_COL_WIDTH: f32 = 30.0;
+// Distance from a column border at which it can be grabbed to resize it
+const RESIZE_GRAB: f32 = 4.0;
+
+/// Identifies a row by the index of each child taken on the way down from the
+/// root, in the order given by [`TreeWalk::children`].
+pub type TreePath = Vec<usize>;
+
+/// Per row state of the view, mirroring the shape of the walked tree. Only
+/// rows which have been expanded at some point get an entry, and collapsing a
+/// row keeps the state of its descendants for when it is expanded again.
+#[derive(Debug, Default)]
 pub struct TreeViewData {
     // cols_cache: Option<Box<[String]>>,
     expanded: bool,
+    children: HashMap<usize, TreeViewData>,
+    // Indices of the children shown, in display order, for the revision,
+    // sort and dimmed rows in `listed`
+    listed: Option<(u64, Option<Sort>, DimmedRows)>,
+    shown: Vec<usize>,
+    // Row each of `shown` starts at, counted from the first one, as of the
+    // last count. Expanded children take more than one.
+    starts: Vec<usize>,
 }
 
-impl Default for TreeViewData {
-    fn default() -> Self {
-        TreeViewData {
-            // cols_cache: None,
-            expanded: false,
-        }
+/// Column the children are ordered by at every level, as an index into
+/// [`TreeWalk::COL_TITLES`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Sort {
+    col: usize,
+    ascending: bool,
+}
+
+impl TreeViewData {
+    fn get_mut(&mut self, path: &[usize]) -> &mut TreeViewData {
+        path.iter()
+            .fold(self, |data, idx| data.children.entry(*idx).or_default())
     }
 }
 
 pub trait TreeWalk: Sized {
     const N_COLS: usize;
+    /// Header of each column, `N_COLS` of them.
+    const COL_TITLES: &'static [&'static str];
     fn children(&self) -> impl Iterator<Item = Self>;
+    /// The child at `idx` in [`TreeWalk::children`].
+    fn child(&self, idx: usize) -> Option<Self> {
+        self.children().nth(idx)
+    }
     fn to_cols(&self) -> Vec<String>;
+    /// Orders siblings by the value shown in column `col`.
+    fn cmp_col(&self, other: &Self, col: usize) -> Ordering;
+    /// Whether the row is of less interest, drawn greyed out or hidden.
+    fn dimmed(&self) -> bool {
+        false
+    }
+    /// Whether the row is dimmed or has dimmed descendants, which keep it
+    /// shown when only the dimmed rows are.
+    fn holds_dimmed(&self) -> bool {
+        self.dimmed()
+    }
     // fn get_data(&self) -> &TreeViewData;
     // fn get_data_mut(&mut self) -> &mut TreeViewData;
 }
 
 impl TreeWalk for () {
     const N_COLS: usize = 0;
+    const COL_TITLES: &'static [&'static str] = &[];
 
     fn children(&self) -> impl Iterator<Item = Self> {
         iter::empty()
@@ -38,31 +97,356 @@ impl TreeWalk for () {
     fn to_cols(&self) -> Vec<String> {
         Vec::new()
     }
+
+    fn cmp_col(&self, _other: &Self, _col: usize) -> Ordering {
+        Ordering::Equal
+    }
+}
+
+/// Which rows are shown, of those [`TreeWalk::dimmed`] tells apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DimmedRows {
+    /// Every row, the dimmed ones greyed out
+    #[default]
+    Shown,
+    Hidden,
+    /// Only the rows which hold dimmed ones
+    Only,
+}
+
+impl DimmedRows {
+    fn keeps<T: TreeWalk>(self, row: &T) -> bool {
+        match self {
+            DimmedRows::Shown => true,
+            DimmedRows::Hidden => !row.dimmed(),
+            DimmedRows::Only => row.holds_dimmed(),
+        }
+    }
 }
 
-pub struct TreeView<T: TreeWalk> {
+pub struct TreeView<'a, T: TreeWalk, Message> {
     tree: T,
-    bounds: Size,
+    selected: Option<&'a TreePath>,
+    on_select: Option<Box<dyn Fn(TreePath) -> Message + 'a>>,
+    dimmed_rows: DimmedRows,
+    tree_id: u64,
+    revision: u64,
+    // Columns shown, as indices into `TreeWalk::COL_TITLES`
+    cols: Vec<usize>,
 }
 
-impl<T: TreeWalk> TreeView<T> {
+impl<'a, T: TreeWalk, Message> TreeView<'a, T, Message> {
     pub fn new(tree: T) -> Self {
         TreeView {
             tree,
-            bounds: Size::ZERO,
+            selected: None,
+            on_select: None,
+            dimmed_rows: DimmedRows::Shown,
+            tree_id: 0,
+            revision: 0,
+            cols: (0..T::N_COLS).collect(),
         }
     }
+
+    /// Highlights the row at `path`, expanding its ancestors the first time
+    /// it is shown.
+    pub fn selected(mut self, path: Option<&'a TreePath>) -> Self {
+        self.selected = path;
+        self
+    }
+
+    pub fn on_select(mut self, on_select: impl Fn(TreePath) -> Message + 'a) -> Self {
+        self.on_select = Some(Box::new(on_select));
+        self
+    }
+
+    /// Identifies the tree shown. When it changes, the rows are collapsed
+    /// and scrolled back to the top, as paths into the old tree are
+    /// meaningless in the new one.
+    pub fn tree_id(mut self, tree_id: u64) -> Self {
+        self.tree_id = tree_id;
+        self
+    }
+
+    /// Changes whenever the tree is modified in place, to count its rows
+    /// again.
+    pub fn revision(mut self, revision: u64) -> Self {
+        self.revision = revision;
+        self
+    }
+
+    /// Shows only the columns at these indices into [`TreeWalk::COL_TITLES`],
+    /// in this order, instead of all of them. The first one is indented to
+    /// show the nesting.
+    pub fn columns(mut self, cols: Vec<usize>) -> Self {
+        self.cols = cols;
+        self
+    }
+
+    /// Whether the dimmed rows are greyed out, left out, or the only ones
+    /// shown.
+    pub fn dimmed_rows(mut self, dimmed_rows: DimmedRows) -> Self {
+        self.dimmed_rows = dimmed_rows;
+        self
+    }
 }
 
 #[derive(Debug)]
 pub struct TreeViewState<P: Paragraph> {
+    tree_id: u64,
+    // Width of each column shown
     col_widths: Vec<f32>,
     top_offset: f32,
+    row_height: f32,
+    // Height of the rows area, below the header
+    view_height: f32,
+    data: TreeViewData,
+    sort: Option<Sort>,
+    // Selection seen during the last layout, so new ones can be revealed
+    selected: Option<TreePath>,
+    // Number of rows of the whole expanded tree, of which only the visible
+    // ones are kept in `rows`
+    row_count: usize,
+    // Revision, sort and dimmed rows `row_count` was counted for, None once
+    // a row is expanded or collapsed. Scrolling leaves it as it is.
+    counted: Option<(u64, Option<Sort>, DimmedRows)>,
+    // Where the scrollbar thumb was grabbed, relative to its top
+    scroll_grab: Option<f32>,
+    // Column being resized, with the cursor x and its width when grabbed
+    col_grab: Option<(usize, f32, f32)>,
+    header_para: Vec<P>,
+    rows: Vec<Row>,
     row_text: Vec<Vec<String>>,
     row_para: Vec<Vec<P>>,
 }
 
-impl<Message, Theme, Renderer, T> Widget<Message, Theme, Renderer> for TreeView<T>
+#[derive(Debug)]
+struct Row {
+    index: usize,
+    path: TreePath,
+    expandable: bool,
+    expanded: bool,
+    dimmed: bool,
+}
+
+impl Row {
+    fn indent(&self) -> f32 {
+        self.path.len() as f32 * INDENT
+    }
+}
+
+impl<P: Paragraph> TreeViewState<P> {
+    /// The header is one row tall, with the rows area below it.
+    fn header_height(&self) -> f32 {
+        self.row_height
+    }
+
+    fn row_at(&self, point: Point) -> Option<&Row> {
+        let idx = ((point.y - self.header_height() - self.top_offset) / self.row_height).floor();
+        if idx < 0.0 {
+            return None;
+        }
+        let first = self.rows.first()?.index;
+        self.rows.get((idx as usize).checked_sub(first)?)
+    }
+
+    fn content_height(&self) -> f32 {
+        self.row_count as f32 * self.row_height
+    }
+
+    fn scroll_to(&mut self, top_offset: f32) {
+        let min = (self.view_height - self.content_height()).min(0.0);
+        self.top_offset = top_offset.clamp(min, 0.0);
+    }
+
+    /// Bounds of the scrollbar track and thumb, relative to the widget, if
+    /// the rows do not fit.
+    fn scrollbar(&self, width: f32) -> Option<(Rectangle, Rectangle)> {
+        let content_height = self.content_height();
+        if content_height <= self.view_height {
+            return None;
+        }
+        let track = Rectangle::new(
+            Point::new(width - SCROLLBAR_WIDTH, self.header_height()),
+            Size::new(SCROLLBAR_WIDTH, self.view_height),
+        );
+        let thumb_height = (self.view_height * self.view_height / content_height)
+            .max(SCROLLBAR_MIN_THUMB)
+            .min(self.view_height);
+        let progress = -self.top_offset / (content_height - self.view_height);
+        let thumb = Rectangle::new(
+            Point::new(track.x, track.y + progress * (self.view_height - thumb_height)),
+            Size::new(SCROLLBAR_WIDTH, thumb_height),
+        );
+        Some((track, thumb))
+    }
+
+    fn drag_thumb(&mut self, thumb_top: f32, thumb_height: f32) {
+        let travel = self.view_height - thumb_height;
+        if travel > 0.0 {
+            let progress = (thumb_top / travel).clamp(0.0, 1.0);
+            self.scroll_to(-progress * (self.content_height() - self.view_height));
+        }
+    }
+
+    /// Column whose right border is under `x`, relative to the widget.
+    fn col_border_at(&self, x: f32) -> Option<usize> {
+        let mut right = 0.0;
+        self.col_widths.iter().position(|width| {
+            right += width;
+            (x - right).abs() <= RESIZE_GRAB
+        })
+    }
+
+    fn col_at(&self, x: f32) -> Option<usize> {
+        let mut right = 0.0;
+        self.col_widths.iter().position(|width| {
+            right += width;
+            x < right
+        })
+    }
+}
+
+/// Walks the expanded part of the tree in display order. Only rows whose
+/// index falls inside `window` are kept, so laying out a huge tree does not
+/// build anything for the rows which are scrolled out of view.
+struct RowCollector<'a> {
+    window: Range<usize>,
+    // Whether every row is walked, to count them and store where each child
+    // starts in `TreeViewData::starts`. Otherwise those are used to seek
+    // straight to the first row of the window, and the walk stops at its end.
+    count_all: bool,
+    revision: u64,
+    sort: Option<Sort>,
+    dimmed_rows: DimmedRows,
+    cols: &'a [usize],
+    // Path whose row index should be recorded in `found`
+    find: Option<&'a [usize]>,
+    found: Option<usize>,
+    count: usize,
+    rows: Vec<Row>,
+    row_text: Vec<Vec<String>>,
+}
+
+impl<'a> RowCollector<'a> {
+    /// Counts all the rows, recording the index of `find` if it is shown.
+    fn counter(
+        revision: u64,
+        sort: Option<Sort>,
+        dimmed_rows: DimmedRows,
+        find: Option<&'a [usize]>,
+    ) -> Self {
+        RowCollector {
+            window: 0..0,
+            count_all: true,
+            revision,
+            sort,
+            dimmed_rows,
+            cols: &[],
+            find,
+            found: None,
+            count: 0,
+            rows: Vec::new(),
+            row_text: Vec::new(),
+        }
+    }
+
+    /// Collects the rows inside `window`, with the text of the columns
+    /// `cols`, once they have been counted.
+    fn window(
+        window: Range<usize>,
+        revision: u64,
+        sort: Option<Sort>,
+        dimmed_rows: DimmedRows,
+        cols: &'a [usize],
+    ) -> Self {
+        RowCollector {
+            window,
+            count_all: false,
+            revision,
+            sort,
+            dimmed_rows,
+            cols,
+            find: None,
+            found: None,
+            count: 0,
+            rows: Vec::new(),
+            row_text: Vec::new(),
+        }
+    }
+
+    /// Collects the rows below `node`, whose own state is `data`.
+    fn collect<T: TreeWalk>(&mut self, node: &T, data: &mut TreeViewData, path: &mut TreePath) {
+        let key = (self.revision, self.sort, self.dimmed_rows);
+        if data.listed != Some(key) {
+            let children: Vec<T> = node.children().collect();
+            let mut shown: Vec<usize> = (0..children.len())
+                .filter(|&idx| self.dimmed_rows.keeps(&children[idx]))
+                .collect();
+            if let Some(sort) = self.sort {
+                shown.sort_by(|&lhs, &rhs| {
+                    let ordering = children[lhs].cmp_col(&children[rhs], sort.col);
+                    if sort.ascending { ordering } else { ordering.reverse() }
+                });
+            }
+            data.shown = shown;
+            data.starts.clear();
+            data.listed = Some(key);
+        }
+
+        let first_row = self.count;
+        let first = if self.count_all {
+            data.starts.clear();
+            0
+        } else {
+            // Skip the children whose rows are all above the window
+            let pos = data
+                .starts
+                .partition_point(|&start| first_row + start <= self.window.start)
+                .saturating_sub(1);
+            self.count += data.starts.get(pos).unwrap_or(&0);
+            pos
+        };
+
+        for pos in first..data.shown.len() {
+            if !self.count_all && self.count >= self.window.end {
+                break;
+            }
+            let idx = data.shown[pos];
+            if self.count_all {
+                data.starts.push(self.count - first_row);
+            }
+            let expanded = data.children.get(&idx).is_some_and(|data| data.expanded);
+            path.push(idx);
+            if self.window.contains(&self.count) {
+                let child = node.child(idx).expect("shown children are listed for this revision");
+                self.rows.push(Row {
+                    index: self.count,
+                    path: path.clone(),
+                    expandable: child
+                        .children()
+                        .any(|grand_child| self.dimmed_rows.keeps(&grand_child)),
+                    expanded,
+                    dimmed: self.dimmed_rows == DimmedRows::Shown && child.dimmed(),
+                });
+                let mut text = child.to_cols();
+                self.row_text
+                    .push(self.cols.iter().map(|&col| mem::take(&mut text[col])).collect());
+            }
+            if self.find == Some(path.as_slice()) {
+                self.found = Some(self.count);
+            }
+            self.count += 1;
+            if let (true, Some(child_data)) = (expanded, data.children.get_mut(&idx)) {
+                let child = node.child(idx).expect("shown children are listed for this revision");
+                self.collect(&child, child_data, path);
+            }
+            path.pop();
+        }
+    }
+}
+
+impl<Message, Theme, Renderer, T> Widget<Message, Theme, Renderer> for TreeView<'_, T, Message>
 where
     Renderer: text::Renderer,
     T: TreeWalk,
@@ -73,8 +457,20 @@ where
 
     fn state(&self) -> tree::State {
         tree::State::new(TreeViewState::<Renderer::Paragraph> {
-            col_widths: vec![200.0; T::N_COLS],
+            tree_id: self.tree_id,
+            col_widths: vec![200.0; self.cols.len()],
             top_offset: 0.0,
+            row_height: 0.0,
+            view_height: 0.0,
+            data: TreeViewData::default(),
+            sort: None,
+            selected: None,
+            row_count: 0,
+            counted: None,
+            scroll_grab: None,
+            col_grab: None,
+            header_para: Vec::new(),
+            rows: Vec::new(),
             row_text: Vec::new(),
             row_para: Vec::new(),
         })
@@ -82,7 +478,7 @@ where
 
     fn size(&self) -> iced::Size<iced::Length> {
         Size {
-            width: Length::Shrink,
+            width: Length::Fill,
             height: Length::Fill,
         }
     }
@@ -99,32 +495,105 @@ where
         let state = tree
             .state
             .downcast_mut::<TreeViewState<Renderer::Paragraph>>();
-
-        state.row_text = self.tree.children().map(|row| row.to_cols()).collect();
-        // println!("row_text: {:?}", &state.row_text);
-        state.row_para = state.row_text.iter().map(|row| {
-            row.iter().zip(&state.col_widths).map(|(col, &width)| {
-                let mut para = Renderer::Paragraph::default();
-                para.update(Text {
-                    content: &col,
-                    bounds: Size::new(width, line_height.to_absolute(size).0),
-                    size,
-                    line_height,
-                    font: renderer.default_font(),
-                    horizontal_alignment: alignment::Horizontal::Left,
-                    vertical_alignment: alignment::Vertical::Top,
-                    shaping: Shaping::Basic,
-                });
-                para
-            }).collect()
-        }).collect();
+        if state.tree_id != self.tree_id {
+            state.tree_id = self.tree_id;
+            state.data = TreeViewData::default();
+            state.top_offset = 0.0;
+            state.selected = None;
+            state.counted = None;
+        }
 
         let width = Length::Fill;
         let height = Length::Fill;
-        layout::sized(limits, width, height, |limits| {
+        let node = layout::sized(limits, width, height, |limits| {
             // println!("{:?}", limits);
             limits.min()
-        })
+        });
+
+        state.row_height = line_height.to_absolute(size).0;
+        state.view_height = (node.size().height - state.header_height()).max(0.0);
+
+        let mut reveal = None;
+        if self.selected != state.selected.as_ref() {
+            if let Some(selected) = self.selected {
+                for depth in 1..selected.len() {
+                    state.data.get_mut(&selected[..depth]).expanded = true;
+                }
+                reveal = Some(selected.as_slice());
+            }
+            state.selected = self.selected.cloned();
+        }
+
+        // Count the rows first, as the visible window depends on the scroll
+        // position which is only known once they have been counted. They
+        // only change along with what `counted` holds, or when revealing the
+        // selection expands its ancestors.
+        let count_key = (self.revision, state.sort, self.dimmed_rows);
+        let mut top_offset = state.top_offset;
+        if state.counted != Some(count_key) || reveal.is_some() {
+            let mut counter =
+                RowCollector::counter(self.revision, state.sort, self.dimmed_rows, reveal);
+            counter.collect(&self.tree, &mut state.data, &mut Vec::new());
+            state.row_count = counter.count;
+            state.counted = Some(count_key);
+            if let Some(found) = counter.found {
+                let row_top = found as f32 * state.row_height;
+                if row_top + top_offset < 0.0 {
+                    top_offset = -row_top;
+                } else if row_top + state.row_height + top_offset > state.view_height {
+                    top_offset = state.view_height - row_top - state.row_height;
+                }
+            }
+        }
+        state.scroll_to(top_offset);
+
+        let first = (-state.top_offset / state.row_height).floor() as usize;
+        let last = ((state.view_height - state.top_offset) / state.row_height).ceil() as usize;
+        let mut collector = RowCollector::window(
+            first..last,
+            self.revision,
+            state.sort,
+            self.dimmed_rows,
+            &self.cols,
+        );
+        collector.collect(&self.tree, &mut state.data, &mut Vec::new());
+        state.rows = collector.rows;
+        state.row_text = collector.row_text;
+
+        let make_para = |content: &str, width: f32| {
+            let mut para = Renderer::Paragraph::default();
+            para.update(Text {
+                content,
+                bounds: Size::new(width, line_height.to_absolute(size).0),
+                size,
+                line_height,
+                font: renderer.default_font(),
+                horizontal_alignment: alignment::Horizontal::Left,
+                vertical_alignment: alignment::Vertical::Top,
+                shaping: Shaping::Basic,
+            });
+            para
+        };
+        state.header_para = self.cols.iter().zip(&state.col_widths).map(|(&col_idx, &width)| {
+            let title = T::COL_TITLES[col_idx];
+            match state.sort {
+                Some(Sort { col, ascending }) if col == col_idx => {
+                    let arrow = if ascending { '\u{2191}' } else { '\u{2193}' };
+                    make_para(&format!("{} {}", title, arrow), width)
+                }
+                _ => make_para(title, width),
+            }
+        }).collect();
+        // println!("row_text: {:?}", &state.row_text);
+        state.row_para = state.row_text.iter().zip(&state.rows).map(|(row_text, row)| {
+            row_text.iter().zip(&state.col_widths).enumerate().map(|(col_idx, (col, &width))| {
+                // The first column is shifted right by the indentation
+                let width = if col_idx == 0 { (width - row.indent()).max(0.0) } else { width };
+                make_para(col, width)
+            }).collect()
+        }).collect();
+
+        node
     }
 
     fn draw(
@@ -141,34 +610,354 @@ where
         let state = tree
             .state
             .downcast_ref::<TreeViewState<Renderer::Paragraph>>();
-        let line_height = 20.0; // TODO
-        let Some(visible_bounds) = layout.bounds().intersection(viewport) else {
+        let line_height = state.row_height;
+        let bounds = layout.bounds();
+        let Some(visible_bounds) = bounds.intersection(viewport) else {
+            return;
+        };
+        let header = Rectangle::new(bounds.position(), Size::new(bounds.width, state.header_height()));
+        if let Some(header_clip) = header.intersection(&visible_bounds) {
+            renderer.fill_quad(
+                Quad {
+                    bounds: header_clip,
+                    ..Quad::default()
+                },
+                HEADER_COLOR,
+            );
+            let mut x = 0.0;
+            for (para, &width) in state.header_para.iter().zip(&state.col_widths) {
+                let top_left = Point::new(bounds.x + x, bounds.y);
+                let clip = Rectangle::new(top_left, Size::new(width, line_height)).intersection(&header_clip);
+                if let Some(clip_some) = clip {
+                    renderer.fill_paragraph(para, top_left, Color::BLACK, clip_some);
+                }
+                x += width;
+                let separator = Rectangle::new(
+                    Point::new(bounds.x + x - 1.0, bounds.y),
+                    Size::new(1.0, state.header_height()),
+                );
+                if let Some(separator) = separator.intersection(&header_clip) {
+                    renderer.fill_quad(
+                        Quad {
+                            bounds: separator,
+                            ..Quad::default()
+                        },
+                        SEPARATOR_COLOR,
+                    );
+                }
+            }
+        }
+
+        let body = Rectangle::new(
+            Point::new(bounds.x, bounds.y + state.header_height()),
+            Size::new(bounds.width, state.view_height),
+        );
+        let Some(visible_bounds) = body.intersection(&visible_bounds) else {
             return;
         };
         // println!("bounds: {:?}, viewport: {:?}", bounds, viewport);
-        for (idx, row) in state.row_para.iter().enumerate() {
-            let y = state.top_offset + idx as f32 * line_height;
+        for (tree_row, row) in state.rows.iter().zip(&state.row_para) {
+            let y = state.header_height() + state.top_offset + tree_row.index as f32 * line_height;
+            if self.selected == Some(&tree_row.path) {
+                let highlight = Rectangle::new(
+                    Point::new(bounds.x, bounds.y + y),
+                    Size::new(bounds.width, line_height),
+                );
+                if let Some(highlight) = highlight.intersection(&visible_bounds) {
+                    renderer.fill_quad(
+                        Quad {
+                            bounds: highlight,
+                            ..Quad::default()
+                        },
+                        SELECTED_COLOR,
+                    );
+                }
+            }
+            if tree_row.expandable {
+                draw_disclosure(
+                    renderer,
+                    Point::new(bounds.x + tree_row.indent() - INDENT, bounds.y + y),
+                    line_height,
+                    tree_row.expanded,
+                    &visible_bounds,
+                );
+            }
+            let text_color = if tree_row.dimmed { DIMMED_COLOR } else { Color::BLACK };
             let mut x = 0.0;
-            for (para, &width) in row.iter().zip(&state.col_widths) {
-                let top_left = Point::new(visible_bounds.x + x, visible_bounds.y + y);
-                let size = Size::new(width, line_height);
+            for (col_idx, (para, &width)) in row.iter().zip(&state.col_widths).enumerate() {
+                let indent = if col_idx == 0 { tree_row.indent() } else { 0.0 };
+                let top_left = Point::new(bounds.x + x + indent, bounds.y + y);
+                let size = Size::new(width - indent, line_height);
                 let clip = Rectangle::new(top_left, size).intersection(&visible_bounds);
                 // println!("clip: {:?}\n  tl: {:?}\n  sz: {:?}\n  vp: {:?}\n  bn: {:?}", clip, top_left, size, viewport, layout.bounds());
                 if let Some(clip_some) = clip {
-                    renderer.fill_paragraph(&para, top_left, Color::BLACK, clip_some);
+                    renderer.fill_paragraph(para, top_left, text_color, clip_some);
                 }
                 x += width;
             }
         }
+
+        if let Some((track, thumb)) = state.scrollbar(bounds.width) {
+            let offset = Vector::new(bounds.x, bounds.y);
+            // A new layer, as text is always drawn above the quads of its own
+            renderer.with_layer(visible_bounds, |renderer| {
+                renderer.fill_quad(
+                    Quad {
+                        bounds: track + offset,
+                        ..Quad::default()
+                    },
+                    SCROLLBAR_COLOR,
+                );
+                renderer.fill_quad(
+                    Quad {
+                        bounds: thumb + offset,
+                        border: Border::with_radius(SCROLLBAR_WIDTH / 2.0),
+                        ..Quad::default()
+                    },
+                    SCROLLBAR_THUMB_COLOR,
+                );
+            });
+        }
+    }
+
+    fn on_event(
+        &mut self,
+        tree: &mut iced::advanced::widget::Tree,
+        event: Event,
+        layout: iced::advanced::Layout<'_>,
+        cursor: mouse::Cursor,
+        _renderer: &Renderer,
+        _clipboard: &mut dyn Clipboard,
+        shell: &mut Shell<'_, Message>,
+        _viewport: &Rectangle,
+    ) -> event::Status {
+        let bounds = layout.bounds();
+        let state = tree
+            .state
+            .downcast_mut::<TreeViewState<Renderer::Paragraph>>();
+        let Event::Mouse(mouse_event) = event else {
+            return event::Status::Ignored;
+        };
+
+        match mouse_event {
+            mouse::Event::CursorMoved { position } => {
+                if let Some((col, grab_x, grab_width)) = state.col_grab {
+                    state.col_widths[col] = (grab_width + position.x - grab_x).max(MIN_COL_WIDTH);
+                    shell.invalidate_layout();
+                    return event::Status::Captured;
+                }
+                let (Some(grab), Some((_, thumb))) = (state.scroll_grab, state.scrollbar(bounds.width)) else {
+                    return event::Status::Ignored;
+                };
+                state.drag_thumb(position.y - bounds.y - state.header_height() - grab, thumb.height);
+                shell.invalidate_layout();
+                return event::Status::Captured;
+            }
+            mouse::Event::ButtonReleased(mouse::Button::Left)
+                if state.scroll_grab.is_some() || state.col_grab.is_some() =>
+            {
+                state.scroll_grab = None;
+                state.col_grab = None;
+                return event::Status::Captured;
+            }
+            mouse::Event::WheelScrolled { delta } if cursor.is_over(bounds) => {
+                let dy = match delta {
+                    mouse::ScrollDelta::Lines { y, .. } => y * WHEEL_ROWS * state.row_height,
+                    mouse::ScrollDelta::Pixels { y, .. } => y,
+                };
+                state.scroll_to(state.top_offset + dy);
+                shell.invalidate_layout();
+                return event::Status::Captured;
+            }
+            mouse::Event::ButtonPressed(mouse::Button::Left) => {}
+            _ => return event::Status::Ignored,
+        }
+
+        let Some(position) = cursor.position_in(bounds) else {
+            return event::Status::Ignored;
+        };
+        if position.y < state.header_height() {
+            if let Some(col) = state.col_border_at(position.x) {
+                state.col_grab = Some((col, position.x, state.col_widths[col]));
+            } else if let Some(col) = state.col_at(position.x) {
+                let col = self.cols[col];
+                // Clicking the sorted column again flips the direction
+                let ascending = !matches!(state.sort, Some(sort) if sort.col == col && sort.ascending);
+                state.sort = Some(Sort { col, ascending });
+                shell.invalidate_layout();
+            }
+            return event::Status::Captured;
+        }
+        if let Some((track, thumb)) = state.scrollbar(bounds.width) {
+            if thumb.contains(position) {
+                state.scroll_grab = Some(position.y - thumb.y);
+                return event::Status::Captured;
+            }
+            if track.contains(position) {
+                // Page towards the click, like most scrollbars do
+                let page = if position.y < thumb.y { state.view_height } else { -state.view_height };
+                state.scroll_to(state.top_offset + page);
+                shell.invalidate_layout();
+                return event::Status::Captured;
+            }
+        }
+        let Some(row) = state.row_at(position) else {
+            return event::Status::Captured;
+        };
+
+        let on_disclosure = position.x >= row.indent() - INDENT && position.x < row.indent();
+        if row.expandable && on_disclosure {
+            let path = row.path.clone();
+            let data = state.data.get_mut(&path);
+            data.expanded = !data.expanded;
+            state.counted = None;
+            shell.invalidate_layout();
+        } else if let Some(on_select) = &self.on_select {
+            shell.publish(on_select(row.path.clone()));
+        }
+        event::Status::Captured
+    }
+
+    fn mouse_interaction(
+        &self,
+        tree: &iced::advanced::widget::Tree,
+        layout: iced::advanced::Layout<'_>,
+        cursor: mouse::Cursor,
+        _viewport: &Rectangle,
+        _renderer: &Renderer,
+    ) -> mouse::Interaction {
+        let state = tree
+            .state
+            .downcast_ref::<TreeViewState<Renderer::Paragraph>>();
+        let on_border = cursor.position_in(layout.bounds()).is_some_and(|position| {
+            position.y < state.header_height() && state.col_border_at(position.x).is_some()
+        });
+        if state.col_grab.is_some() || on_border {
+            mouse::Interaction::ResizingHorizontally
+        } else {
+            mouse::Interaction::Idle
+        }
+    }
+}
+
+/// Draws the WinDirStat style box holding a `+` or `-` in the indentation
+/// slot starting at `top_left`.
+fn draw_disclosure<Renderer: text::Renderer>(
+    renderer: &mut Renderer,
+    top_left: Point,
+    line_height: f32,
+    expanded: bool,
+    clip: &Rectangle,
+) {
+    let center = Point::new(top_left.x + INDENT / 2.0, top_left.y + line_height / 2.0);
+    let half = DISCLOSURE_SIZE / 2.0;
+    let frame = Rectangle::new(
+        Point::new(center.x - half, center.y - half),
+        Size::new(DISCLOSURE_SIZE, DISCLOSURE_SIZE),
+    );
+    if !clip.contains(frame.position()) {
+        return;
+    }
+    renderer.fill_quad(
+        Quad {
+            bounds: frame,
+            border: Border {
+                color: DISCLOSURE_COLOR,
+                width: 1.0,
+                radius: 0.0.into(),
+            },
+            ..Quad::default()
+        },
+        Color::WHITE,
+    );
+    let bar = half - 2.0;
+    let mut marks = vec![Rectangle::new(
+        Point::new(center.x - bar, center.y - 0.5),
+        Size::new(2.0 * bar, 1.0),
+    )];
+    if !expanded {
+        marks.push(Rectangle::new(
+            Point::new(center.x - 0.5, center.y - bar),
+            Size::new(1.0, 2.0 * bar),
+        ));
+    }
+    for mark in marks {
+        renderer.fill_quad(
+            Quad {
+                bounds: mark,
+                ..Quad::default()
+            },
+            Color::BLACK,
+        );
     }
 }
 
-impl<'a, Message, Theme, Renderer, T> From<TreeView<T>> for Element<'a, Message, Theme, Renderer>
+impl<'a, Message, Theme, Renderer, T> From<TreeView<'a, T, Message>> for Element<'a, Message, Theme, Renderer>
 where
     Renderer: text::Renderer,
     T: TreeWalk + 'a,
+    Message: 'a,
 {
-    fn from(tree_view: TreeView<T>) -> Self {
+    fn from(tree_view: TreeView<'a, T, Message>) -> Self {
         Self::new(tree_view)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Five children per node, three levels deep, named after their path.
+    struct Node(String);
+
+    impl TreeWalk for Node {
+        const N_COLS: usize = 1;
+        const COL_TITLES: &'static [&'static str] = &["Name"];
+
+        fn children(&self) -> impl Iterator<Item = Self> {
+            let n_children = if self.0.len() < 3 { 5 } else { 0 };
+            (0..n_children).map(|idx| Node(format!("{}{}", self.0, idx)))
+        }
+
+        fn to_cols(&self) -> Vec<String> {
+            vec![self.0.clone()]
+        }
+
+        fn cmp_col(&self, other: &Self, _col: usize) -> Ordering {
+            self.0.cmp(&other.0)
+        }
+
+        fn dimmed(&self) -> bool {
+            self.0.ends_with('2')
+        }
+    }
+
+    #[test]
+    fn windows_match_the_full_walk() {
+        let root = Node(String::new());
+        let mut data = TreeViewData::default();
+        for path in [&[1][..], &[3], &[3, 4], &[3, 0], &[4]] {
+            data.get_mut(path).expanded = true;
+        }
+        // Collapsed, so its expanded child is not shown
+        data.get_mut(&[0, 1]).expanded = true;
+
+        let sorts = [None, Some(Sort { col: 0, ascending: false })];
+        for (sort, dimmed_rows) in sorts.into_iter().zip([DimmedRows::Shown, DimmedRows::Hidden]) {
+            let mut counter = RowCollector::counter(0, sort, dimmed_rows, None);
+            counter.collect(&root, &mut data, &mut Vec::new());
+            let mut all = RowCollector::window(0..usize::MAX, 0, sort, dimmed_rows, &[0]);
+            all.collect(&root, &mut data, &mut Vec::new());
+            assert_eq!(all.rows.len(), counter.count);
+
+            for start in 0..counter.count {
+                for end in start..counter.count + 2 {
+                    let mut window = RowCollector::window(start..end, 0, sort, dimmed_rows, &[0]);
+                    window.collect(&root, &mut data, &mut Vec::new());
+                    let expected = &all.row_text[start..end.min(counter.count)];
+                    assert_eq!(window.row_text, expected, "{:?} {:?}", sort, start..end);
+                }
+            }
+        }
+    }
+}
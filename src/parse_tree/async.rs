@@ -1,53 +1,156 @@
 use std::{
-    collections::LinkedList,
-    ffi::OsStr, io,
-    path::Path,
+    collections::{HashSet, LinkedList},
+    ffi::OsStr,
+    future::Future,
+    mem,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
 };
 
-use super::{fs_crossing, read_dir_entry, Config, Dir, Elem, FileError, WalkContext};
+use tokio::{sync::Semaphore, task};
 
-pub async fn parse_tree<P: AsRef<Path>>(path: P, config: Config) -> io::Result<(Dir, Vec<FileError>)> {
-    let root_can = path.as_ref().to_owned().canonicalize()?;
-    let name = root_can.as_os_str().to_owned();
+use super::{read_dir_entry, Config, Dir, FileError, FileId, Root, Tree, WalkContext};
 
-    let context = WalkContext {
-        root_fs: if config.same_filesystem { fs_crossing::device_num(&root_can)? } else { 0 },
-        config,
+/// Walks the tree at `path` on the current tokio runtime, giving the same
+/// result as the other walkers. The file system calls run on the blocking
+/// pool, so awaiting this doesn't stall the executor. At most `max_reads`
+/// directories are read at the same time, as the blocking pool would
+/// otherwise grow a thread per pending directory.
+pub(super) async fn walk(
+    path: PathBuf,
+    config: Config,
+    counted_links: HashSet<FileId>,
+    ignored: bool,
+    max_reads: usize,
+) -> Result<(Tree, Vec<FileError>), FileError> {
+    let Root {
+        dir: root,
+        path: root_can,
+        context,
+        errors: mut open_errors,
+    } = task::spawn_blocking(move || Root::open(&path, config, counted_links, ignored))
+        .await
+        .expect("the walker panicked")?;
+
+    let ancestors = Arc::new(Ancestors {
+        id: root.id,
+        path: root_can.clone(),
+        parent: None,
+    });
+    let context = Arc::new(context);
+    let walk = Walk {
+        context: context.clone(),
+        reads: Arc::new(Semaphore::new(max_reads)),
     };
+    let (dir, errors) = walk.recurse_dir(root, root_can, ancestors, 0).await;
+    // The tasks are all done, and dropped their handles to the context along
+    // with their futures
+    let context = Arc::try_unwrap(context).ok().expect("the walk is over");
+    let tree = task::spawn_blocking(move || context.into_tree(&dir))
+        .await
+        .expect("the walker panicked");
+    open_errors.extend(errors);
+    Ok((tree, open_errors))
+}
 
-    let mut dir = Dir::new(name);
-    let errors = recurse_dir(&mut dir, &root_can.as_path(), &context);
-    Ok((dir, errors.into_iter().collect()))
+/// Like `super::Ancestors`, but owned so that it can be moved into tasks.
+struct Ancestors {
+    id: FileId,
+    path: PathBuf,
+    parent: Option<Arc<Ancestors>>,
 }
 
-async fn recurse_dir(dir: &mut Dir, path: &Path, context: &WalkContext) -> LinkedList<FileError> {
-    let mut errors = LinkedList::new();
-    let children = read_dir_entry(&path, context, |err| errors.push_back(err));
-    for child in children {
-        match child {
-            Elem::Dir(d) => dir.dirs.push(d),
-            Elem::File(f) => {
-                dir.size.files_size += f.size;
-                dir.files.push(f);
-            },
+impl Ancestors {
+    fn find(&self, id: FileId) -> Option<&Path> {
+        let mut ancestor = Some(self);
+        while let Some(a) = ancestor {
+            if a.id == id {
+                return Some(&a.path);
+            }
+            ancestor = a.parent.as_deref();
         }
+        None
     }
+}
+
+type DirFuture = Pin<Box<dyn Future<Output = (Dir, LinkedList<FileError>)> + Send>>;
+
+#[derive(Clone)]
+struct Walk {
+    context: Arc<WalkContext>,
+    reads: Arc<Semaphore>,
+}
+
+impl Walk {
+    /// Walks the children of `dir`, found at `path` and `depth` levels below
+    /// the root, each subdirectory in its own task. `ancestors` starts with
+    /// `dir` itself.
+    fn recurse_dir(
+        self,
+        mut dir: Dir,
+        path: PathBuf,
+        ancestors: Arc<Ancestors>,
+        depth: usize,
+    ) -> DirFuture {
+        Box::pin(async move {
+            if self.context.is_cancelled() {
+                dir.incomplete = true;
+                return (dir, LinkedList::new());
+            }
+            let permit = self.reads.clone().acquire_owned().await.unwrap();
+            let context = self.context.clone();
+            let listed = self.context.listed(&dir, depth);
+            let (path, children, mut errors) = task::spawn_blocking(move || {
+                let _permit = permit;
+                let mut errors = LinkedList::new();
+                let children = read_dir_entry(&path, listed, &context, |err| errors.push_back(err));
+                (path, children, errors)
+            })
+            .await
+            .expect("the walker panicked");
+            dir.push_children(children, listed, &self.context);
+
+            let mut child_errors = LinkedList::new();
+            let mut tasks = Vec::with_capacity(dir.dirs.len());
+            for d in mem::take(&mut dir.dirs) {
+                let child_path = path.join::<&OsStr>(d.name.as_ref());
+                if let Some(ancestor) = ancestors.find(d.id) {
+                    self.context.count_error();
+                    tasks.push(Err((d, FileError::symlink_loop(child_path, ancestor))));
+                    continue;
+                }
+                let child_ancestors = Arc::new(Ancestors {
+                    id: d.id,
+                    path: child_path.clone(),
+                    parent: Some(ancestors.clone()),
+                });
+                let walk = self
+                    .clone()
+                    .recurse_dir(d, child_path, child_ancestors, depth + 1);
+                tasks.push(Ok(tokio::spawn(walk)));
+            }
+            // Await in order, so that the children keep the order they were
+            // read in
+            for child in tasks {
+                match child {
+                    Ok(handle) => {
+                        let (d, mut errors) = handle.await.expect("the walker panicked");
+                        dir.dirs.push(d);
+                        child_errors.append(&mut errors);
+                    }
+                    Err((d, err)) => {
+                        dir.dirs.push(d);
+                        child_errors.push_back(err);
+                    }
+                }
+            }
 
-    let mut child_ = dir
-        .dirs
-        .par_iter_mut()
-        .map(|d| recurse_dir(d, &path.to_owned().join::<&OsStr>(d.name.as_ref()), context))
-        .reduce(
-            || LinkedList::new(),
-            |mut lhs, mut rhs| {
-                lhs.append(&mut rhs);
-                lhs
-            },
-        );
-
-    dir.size.total_size = dir.size.files_size + dir.dirs.iter().map(|d| d.size.total_size).sum::<u64>();
-    if errors.len() > 0 {
-        child_errors.append(&mut errors);
+            dir.sum_children(&self.context);
+            if !errors.is_empty() {
+                child_errors.append(&mut errors);
+            }
+            (dir, child_errors)
+        })
     }
-    child_errors
 }
@@ -0,0 +1,100 @@
+use std::path::Path;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// Include and exclude glob patterns, each matched against both the name and
+/// the full path of an entry.
+///
+/// A pattern ending in `/` only matches directories. Include patterns only
+/// apply to files: every directory which isn't excluded is walked, so that
+/// included files are found at any depth.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    exclude_dirs: GlobSet,
+}
+
+impl PathFilter {
+    pub fn new<S: AsRef<str>>(include: &[S], exclude: &[S]) -> Result<Self, globset::Error> {
+        let include = if include.is_empty() {
+            None
+        } else {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in include {
+                builder.add(Glob::new(pattern.as_ref())?);
+            }
+            Some(builder.build()?)
+        };
+
+        let mut exclude_builder = GlobSetBuilder::new();
+        let mut exclude_dirs_builder = GlobSetBuilder::new();
+        for pattern in exclude {
+            let pattern = pattern.as_ref();
+            match pattern.strip_suffix('/') {
+                Some(dir_pattern) => exclude_dirs_builder.add(Glob::new(dir_pattern)?),
+                None => exclude_builder.add(Glob::new(pattern)?),
+            };
+        }
+
+        Ok(PathFilter {
+            include,
+            exclude: exclude_builder.build()?,
+            exclude_dirs: exclude_dirs_builder.build()?,
+        })
+    }
+
+    /// Whether the filter keeps every entry.
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_empty() && self.exclude_dirs.is_empty()
+    }
+
+    /// Whether the entry at `path` should be left out of the tree.
+    pub fn excludes(&self, path: &Path, is_dir: bool) -> bool {
+        let matches = |set: &GlobSet| {
+            set.is_match(path) || path.file_name().is_some_and(|name| set.is_match(name))
+        };
+        if matches(&self.exclude) || (is_dir && matches(&self.exclude_dirs)) {
+            return true;
+        }
+        match &self.include {
+            Some(include) if !is_dir => !matches(include),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directory_patterns_only_match_directories() {
+        let filter = PathFilter::new(&[] as &[&str], &["target/"]).unwrap();
+        assert!(filter.excludes(Path::new("/src/target"), true));
+        assert!(!filter.excludes(Path::new("/src/target"), false));
+        assert!(!filter.excludes(Path::new("/src/target/debug"), true));
+    }
+
+    #[test]
+    fn include_patterns_only_apply_to_files() {
+        let filter = PathFilter::new(&["*.rs"], &[]).unwrap();
+        assert!(!filter.excludes(Path::new("/src/main.rs"), false));
+        assert!(filter.excludes(Path::new("/src/Cargo.toml"), false));
+        // Directories are walked for the files they hold
+        assert!(!filter.excludes(Path::new("/src/parse_tree"), true));
+        // Matched on the full path as well as the name
+        let filter = PathFilter::new(&["/src/*/*.toml"], &[]).unwrap();
+        assert!(!filter.excludes(Path::new("/src/crate/Cargo.toml"), false));
+    }
+
+    #[test]
+    fn exclude_wins_over_include() {
+        let filter = PathFilter::new(&["*.rs"], &["generated_*", "build/"]).unwrap();
+        assert!(filter.excludes(Path::new("/src/generated_parser.rs"), false));
+        assert!(filter.excludes(Path::new("/src/build"), true));
+        assert!(!filter.excludes(Path::new("/src/parser.rs"), false));
+        assert!(PathFilter::default().is_empty());
+        assert!(!filter.is_empty());
+    }
+}
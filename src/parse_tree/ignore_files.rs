@@ -0,0 +1,101 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use super::{FileError, FileErrorKind};
+
+/// Names of the files holding ignore patterns, looked for in every directory.
+pub const IGNORE_FILE_NAMES: [&str; 2] = [".gitignore", ".ignore"];
+
+/// The ignore files found so far during a walk, keyed by the directory they
+/// apply to, along with the global git excludes.
+pub struct IgnoreFiles {
+    global: Gitignore,
+    by_dir: RwLock<HashMap<PathBuf, Arc<Gitignore>>>,
+}
+
+impl IgnoreFiles {
+    pub fn new() -> Self {
+        // A broken global excludes file shouldn't stop the walk
+        let (global, _) = Gitignore::global();
+        IgnoreFiles {
+            global,
+            by_dir: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Reads the ignore files of `dir`, if it has any. Must be called before
+    /// asking about any entry below `dir`.
+    pub fn load(&self, dir: &Path, mut err_collect: impl FnMut(FileError)) {
+        let mut builder = GitignoreBuilder::new(dir);
+        for name in IGNORE_FILE_NAMES {
+            let file = dir.join(name);
+            if !file.is_file() {
+                continue;
+            }
+            if let Some(err) = builder.add(&file) {
+                err_collect(FileError {
+                    file,
+                    error: err.to_string(),
+                    kind: FileErrorKind::Io,
+                });
+            }
+        }
+        match builder.build() {
+            Ok(gitignore) if !gitignore.is_empty() => {
+                self.by_dir
+                    .write()
+                    .unwrap()
+                    .insert(dir.to_owned(), Arc::new(gitignore));
+            }
+            Ok(_) => {}
+            Err(err) => err_collect(FileError {
+                file: dir.to_owned(),
+                error: err.to_string(),
+                kind: FileErrorKind::Io,
+            }),
+        }
+    }
+
+    /// Reads the ignore files of the directories above `root`, up to the top
+    /// of the git repository holding it, which apply to the entries below
+    /// `root` as well. Returns whether git ignores `root` itself.
+    pub fn load_ancestors(&self, root: &Path, mut err_collect: impl FnMut(FileError)) -> bool {
+        let Some(top) = root.ancestors().find(|dir| dir.join(".git").exists()) else {
+            return false;
+        };
+        // From the top of the repository down to `root`
+        let mut dirs: Vec<&Path> = root.ancestors().take_while(|dir| *dir != top).collect();
+        dirs.push(top);
+        dirs.reverse();
+        for pair in dirs.windows(2) {
+            self.load(pair[0], &mut err_collect);
+            if self.is_ignored(pair[1], true) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Whether git would ignore `path`, whose parent directory isn't ignored.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if is_dir && path.file_name().is_some_and(|name| name == ".git") {
+            return true;
+        }
+        // The innermost ignore file with a matching pattern decides
+        let by_dir = self.by_dir.read().unwrap();
+        for dir in path.ancestors().skip(1) {
+            if let Some(gitignore) = by_dir.get(dir) {
+                let matched = gitignore.matched(path, is_dir);
+                if !matched.is_none() {
+                    return matched.is_ignore();
+                }
+            }
+        }
+        self.global.matched(path, is_dir).is_ignore()
+    }
+}
@@ -0,0 +1,579 @@
+use std::{
+    collections::LinkedList,
+    ffi::{CStr, CString, OsStr, OsString},
+    io, mem,
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+        unix::ffi::{OsStrExt, OsStringExt},
+    },
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
+
+use super::{
+    build_entry, finish_listing,
+    stat::{blocks_to_bytes, system_time, FileKind, Stat},
+    Ancestors, Dir, Elem, FileError, LabelError, Listed, MetadataFields, ReadLimit, Root, Tree,
+    WalkContext,
+};
+
+/// Size of the buffer `getdents64` fills, enough for a few hundred entries.
+const DIRENT_BUF_SIZE: usize = 32 * 1024;
+
+/// Depth below which directories are walked by `walk_deep`, as each level of
+/// recursion takes stack space and keeps its directory open.
+const MAX_RECURSION_DEPTH: usize = 32;
+
+/// Directories `walk_deep` keeps open on top of the one it starts from.
+const OPEN_DEEP_DIRS: usize = 16;
+
+/// Reads the entries of a directory open as the given descriptor, found at
+/// the given path, like `read_dir_entry`.
+pub(super) type ReadDir =
+    fn(BorrowedFd, &Path, Listed, &WalkContext, &mut dyn FnMut(FileError)) -> Vec<Elem>;
+
+/// Walks the tree on the current rayon pool like `parallel::walk`, but keeps
+/// the directories open and reaches their entries relative to them, instead
+/// of resolving full paths from the root every time.
+pub(super) fn walk(root: Root) -> (Tree, Vec<FileError>) {
+    walk_with(root, read_dir_at)
+}
+
+/// Like `walk`, reading each directory with `read`.
+pub(super) fn walk_with(root: Root, read: ReadDir) -> (Tree, Vec<FileError>) {
+    let Root {
+        mut dir,
+        path,
+        context,
+        mut errors,
+    } = root;
+    let ancestors = Ancestors {
+        id: dir.id,
+        path: &path,
+        parent: None,
+    };
+    match open_dir(None, path.as_os_str(), true) {
+        Ok(fd) => errors.extend(recurse_dir(
+            &mut dir, fd, &path, &ancestors, 0, &context, read,
+        )),
+        Err(err) => {
+            context.count_error();
+            errors.push(err.label(&path));
+        }
+    }
+    (context.into_tree(&dir), errors)
+}
+
+fn recurse_dir(
+    dir: &mut Dir,
+    fd: OwnedFd,
+    path: &Path,
+    ancestors: &Ancestors,
+    depth: usize,
+    context: &WalkContext,
+    read: ReadDir,
+) -> LinkedList<FileError> {
+    if depth >= MAX_RECURSION_DEPTH {
+        return walk_deep(dir, fd, path, ancestors, depth, context, read);
+    }
+    if context.is_cancelled() {
+        dir.incomplete = true;
+        return LinkedList::new();
+    }
+    let mut errors = LinkedList::new();
+    let permit = context.read_limit.as_ref().map(ReadLimit::acquire);
+    let listed = context.listed(dir, depth);
+    let children = read(fd.as_fd(), path, listed, context, &mut |err| {
+        errors.push_back(err)
+    });
+    drop(permit);
+    dir.push_children(children, listed, context);
+
+    let mut child_errors = dir
+        .dirs
+        .par_iter_mut()
+        .map(|d| {
+            let child_path = path.join::<&OsStr>(d.name.as_ref());
+            if let Some(ancestor) = ancestors.find(d.id) {
+                context.count_error();
+                return LinkedList::from([FileError::symlink_loop(child_path, ancestor)]);
+            }
+            let child_fd = match open_dir(
+                Some(fd.as_fd()),
+                d.get_os_name(),
+                context.config.follow_symlinks,
+            ) {
+                Ok(child_fd) => child_fd,
+                Err(err) => {
+                    context.count_error();
+                    return LinkedList::from([err.label(&child_path)]);
+                }
+            };
+            let child_ancestors = Ancestors {
+                id: d.id,
+                path: &child_path,
+                parent: Some(ancestors),
+            };
+            recurse_dir(
+                d,
+                child_fd,
+                &child_path,
+                &child_ancestors,
+                depth + 1,
+                context,
+                read,
+            )
+        })
+        .reduce(LinkedList::new, |mut lhs, mut rhs| {
+            lhs.append(&mut rhs);
+            lhs
+        });
+
+    dir.sum_children(context);
+    if !errors.is_empty() {
+        child_errors.append(&mut errors);
+    }
+    child_errors
+}
+
+/// A directory being walked by `walk_deep`.
+struct Frame {
+    dir: Dir,
+    /// Its subdirectories left to walk, the next one last
+    pending: Vec<Dir>,
+    /// Closed unless it is among the deepest ones
+    fd: Option<OwnedFd>,
+}
+
+/// Like `recurse_dir`, but on the current thread and with a stack of its own
+/// rather than recursion, so that however deep the tree goes, neither the
+/// stack nor the open descriptors run out. Only the deepest directories are
+/// kept open, the others are opened again from the nearest open one when
+/// coming back to them.
+fn walk_deep(
+    dir: &mut Dir,
+    fd: OwnedFd,
+    path: &Path,
+    ancestors: &Ancestors,
+    depth: usize,
+    context: &WalkContext,
+    read: ReadDir,
+) -> LinkedList<FileError> {
+    let mut errors = LinkedList::new();
+    let mut path = path.to_owned();
+    let base = mem::replace(dir, Dir::new(OsString::new(), None, dir.id));
+    let mut stack = vec![enter(base, fd, &path, depth, context, read, &mut errors)];
+    loop {
+        let top = stack.len() - 1;
+        let Some(d) = stack[top].pending.pop() else {
+            let mut frame = stack.pop().unwrap();
+            frame.dir.sum_children(context);
+            match stack.last_mut() {
+                Some(parent) => {
+                    path.pop();
+                    parent.dir.dirs.push(frame.dir);
+                    continue;
+                }
+                None => {
+                    *dir = frame.dir;
+                    return errors;
+                }
+            }
+        };
+        if stack[top].fd.is_none() {
+            if let Err(err) = reopen(&mut stack, &path, context) {
+                context.count_error();
+                errors.push_back(err);
+                // The subdirectories left can't be reached, they stay empty
+                let frame = &mut stack[top];
+                frame.dir.dirs.push(d);
+                frame.dir.dirs.extend(frame.pending.drain(..).rev());
+                continue;
+            }
+        }
+        path.push::<&OsStr>(d.name.as_ref());
+        // The stack holds the ancestors of d below `ancestors`, the
+        // innermost last
+        let ancestor = match stack.iter().position(|f| f.dir.id == d.id) {
+            Some(i) => path.ancestors().nth(stack.len() - i),
+            None => ancestors.find(d.id),
+        };
+        if let Some(ancestor) = ancestor {
+            context.count_error();
+            errors.push_back(FileError::symlink_loop(path.clone(), ancestor));
+            path.pop();
+            stack[top].dir.dirs.push(d);
+            continue;
+        }
+        let parent = stack[top].fd.as_ref().expect("opened again above");
+        match open_dir(
+            Some(parent.as_fd()),
+            d.get_os_name(),
+            context.config.follow_symlinks,
+        ) {
+            Ok(fd) => {
+                let frame = enter(
+                    d,
+                    fd,
+                    &path,
+                    depth + stack.len(),
+                    context,
+                    read,
+                    &mut errors,
+                );
+                stack.push(frame);
+                // Only the base and the deepest directories stay open
+                if let Some(i) = (stack.len() - 1).checked_sub(OPEN_DEEP_DIRS) {
+                    if i > 0 {
+                        stack[i].fd = None;
+                    }
+                }
+            }
+            Err(err) => {
+                context.count_error();
+                errors.push_back(err.label(&path));
+                path.pop();
+                stack[top].dir.dirs.push(d);
+            }
+        }
+    }
+}
+
+/// Reads `dir`, open as `fd` at `path` and `depth` levels below the root,
+/// for `walk_deep`.
+fn enter(
+    mut dir: Dir,
+    fd: OwnedFd,
+    path: &Path,
+    depth: usize,
+    context: &WalkContext,
+    read: ReadDir,
+    errors: &mut LinkedList<FileError>,
+) -> Frame {
+    if context.is_cancelled() {
+        dir.incomplete = true;
+        return Frame {
+            dir,
+            pending: Vec::new(),
+            fd: None,
+        };
+    }
+    let permit = context.read_limit.as_ref().map(ReadLimit::acquire);
+    let listed = context.listed(&dir, depth);
+    let children = read(fd.as_fd(), path, listed, context, &mut |err| {
+        errors.push_back(err)
+    });
+    drop(permit);
+    dir.push_children(children, listed, context);
+    let mut pending = mem::take(&mut dir.dirs);
+    pending.reverse();
+    Frame {
+        dir,
+        pending,
+        fd: Some(fd),
+    }
+}
+
+/// Opens again the directory on top of `stack`, found at `path`, from the
+/// nearest one still open below it. The base of the stack always is.
+fn reopen(stack: &mut [Frame], path: &Path, context: &WalkContext) -> Result<(), FileError> {
+    let top = stack.len() - 1;
+    let open = stack.iter().rposition(|f| f.fd.is_some()).unwrap();
+    for i in open + 1..=top {
+        let parent = stack[i - 1].fd.as_ref().unwrap().as_fd();
+        let fd = open_dir(
+            Some(parent),
+            stack[i].dir.get_os_name(),
+            context.config.follow_symlinks,
+        )
+        .map_err(|err| err.label(path.ancestors().nth(top - i).unwrap()))?;
+        stack[i].fd = Some(fd);
+        // The ones on the way are only kept when among the deepest
+        if i - 1 > open && i - 1 + OPEN_DEEP_DIRS <= top {
+            stack[i - 1].fd = None;
+        }
+    }
+    Ok(())
+}
+
+/// Like `read_dir_entry`, for the directory open as `fd`.
+fn read_dir_at(
+    fd: BorrowedFd,
+    path: &Path,
+    listed: Listed,
+    context: &WalkContext,
+    err_collect: &mut dyn FnMut(FileError),
+) -> Vec<Elem> {
+    let mut err_collect = |err| {
+        context.count_error();
+        err_collect(err)
+    };
+    let mut elems = Vec::new();
+    let options = statx_options(fd, &context.config.metadata);
+    let mut entries = DirEntries::new(fd);
+    loop {
+        let name = match entries.next_name() {
+            Ok(Some(name)) => name,
+            Ok(None) => break,
+            Err(err) => {
+                err_collect(err.label(path));
+                break;
+            }
+        };
+        let elem = stat_at(fd, &name, false, options)
+            .map_err(|err| err.label(path))
+            .and_then(|meta| {
+                build_entry(
+                    OsString::from_vec(name.as_bytes().to_vec()),
+                    path,
+                    meta,
+                    || stat_at(fd, &name, true, options),
+                    context,
+                    &mut err_collect,
+                )
+            });
+        match elem {
+            Ok(Some(elem)) => elems.push(elem),
+            Ok(None) => {}
+            Err(err) => err_collect(err),
+        }
+    }
+    finish_listing(path, listed, &mut elems, context, &mut err_collect);
+    elems
+}
+
+/// Opens the directory `name`, relative to `parent` if given.
+fn open_dir(
+    parent: Option<BorrowedFd>,
+    name: &OsStr,
+    follow_symlinks: bool,
+) -> io::Result<OwnedFd> {
+    let name = CString::new(name.as_bytes())?;
+    let mut flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+    if !follow_symlinks {
+        flags |= libc::O_NOFOLLOW;
+    }
+    let dir_fd = parent.map_or(libc::AT_FDCWD, |fd| fd.as_raw_fd());
+    // SAFETY: `name` is a valid C string for the duration of the call
+    let fd = unsafe { libc::openat(dir_fd, name.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` was just opened and belongs to nobody else
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Fields asked from `statx`, those every node needs. Others may be left
+/// out by the filesystem, saving it work, unless `Config::metadata` asks for
+/// them.
+const STATX_MASK: u32 = libc::STATX_TYPE
+    | libc::STATX_INO
+    | libc::STATX_NLINK
+    | libc::STATX_SIZE
+    | libc::STATX_BLOCKS
+    | libc::STATX_MTIME;
+
+/// `f_type` of the filesystems whose attributes live on a server.
+const NETWORK_FS_MAGICS: [i64; 9] = [
+    0x6969,     // NFS
+    0x517b,     // SMB
+    0xff534d42, // CIFS
+    0xfe534d42, // SMB2
+    0x00c36400, // Ceph
+    0x01021997, // 9P
+    0x5346414f, // AFS
+    0x73757245, // Coda
+    0x65735546, // FUSE, often sshfs
+];
+
+/// Set once `statx` turned out to be unavailable, as before Linux 4.11 or
+/// under some seccomp filters, to use `fstatat` from then on.
+static NO_STATX: AtomicBool = AtomicBool::new(false);
+
+/// How the entries of a directory are passed to `statx`.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct StatxOptions {
+    pub sync_flags: libc::c_int,
+    pub mask: u32,
+}
+
+/// Options for the `statx` calls on the entries of the directory open as
+/// `fd`, asking for the fields in `metadata` on top of `STATX_MASK`.
+pub(super) fn statx_options(fd: BorrowedFd, metadata: &MetadataFields) -> StatxOptions {
+    let mut mask = STATX_MASK;
+    for (kept, field) in [
+        (metadata.accessed, libc::STATX_ATIME),
+        (metadata.changed, libc::STATX_CTIME),
+        (metadata.uid, libc::STATX_UID),
+        (metadata.gid, libc::STATX_GID),
+        (metadata.mode, libc::STATX_MODE),
+    ] {
+        if kept {
+            mask |= field;
+        }
+    }
+    StatxOptions {
+        sync_flags: sync_flags(fd),
+        mask,
+    }
+}
+
+/// Network filesystems are told not to sync the attributes with the server,
+/// which would cost a round trip per entry.
+fn sync_flags(fd: BorrowedFd) -> libc::c_int {
+    let mut fs = mem::MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: `fs` is large enough for a `statfs` and only read once filled
+    if unsafe { libc::fstatfs(fd.as_raw_fd(), fs.as_mut_ptr()) } < 0 {
+        return libc::AT_STATX_SYNC_AS_STAT;
+    }
+    // SAFETY: fstatfs succeeded, so it filled `fs`
+    let f_type = unsafe { fs.assume_init() }.f_type;
+    #[allow(clippy::unnecessary_cast)]
+    if NETWORK_FS_MAGICS.contains(&(f_type as i64)) {
+        libc::AT_STATX_DONT_SYNC
+    } else {
+        libc::AT_STATX_SYNC_AS_STAT
+    }
+}
+
+/// The metadata of the entry `name` of the directory open as `fd`, or of its
+/// target with `follow`.
+pub(super) fn stat_at(
+    fd: BorrowedFd,
+    name: &CStr,
+    follow: bool,
+    options: StatxOptions,
+) -> io::Result<Stat> {
+    let follow_flags = if follow { 0 } else { libc::AT_SYMLINK_NOFOLLOW };
+    if !NO_STATX.load(Ordering::Relaxed) {
+        let mut stx = mem::MaybeUninit::<libc::statx>::uninit();
+        // SAFETY: `stx` is large enough for a `statx` and only read once
+        // filled
+        let ret = unsafe {
+            libc::statx(
+                fd.as_raw_fd(),
+                name.as_ptr(),
+                follow_flags | options.sync_flags,
+                options.mask,
+                stx.as_mut_ptr(),
+            )
+        };
+        if ret == 0 {
+            // SAFETY: statx succeeded, so it filled `stx`
+            return Ok(stat_from_statx(unsafe { &stx.assume_init() }));
+        }
+        let err = io::Error::last_os_error();
+        if !matches!(err.raw_os_error(), Some(libc::ENOSYS | libc::EPERM)) {
+            return Err(err);
+        }
+        NO_STATX.store(true, Ordering::Relaxed);
+    }
+    fstat_at(fd, name, follow_flags)
+}
+
+pub(super) fn stat_from_statx(stx: &libc::statx) -> Stat {
+    let returned = |field| stx.stx_mask & field != 0;
+    let time = |field, time: libc::statx_timestamp| {
+        returned(field)
+            .then(|| system_time(time.tv_sec, i64::from(time.tv_nsec)))
+            .flatten()
+    };
+    Stat {
+        kind: FileKind::from_mode(u32::from(stx.stx_mode)),
+        dev: libc::makedev(stx.stx_dev_major, stx.stx_dev_minor),
+        ino: stx.stx_ino,
+        nlink: u64::from(stx.stx_nlink),
+        size: stx.stx_size,
+        allocated: blocks_to_bytes(stx.stx_blocks),
+        modified: time(libc::STATX_MTIME, stx.stx_mtime),
+        accessed: time(libc::STATX_ATIME, stx.stx_atime),
+        changed: time(libc::STATX_CTIME, stx.stx_ctime),
+        uid: returned(libc::STATX_UID).then_some(stx.stx_uid),
+        gid: returned(libc::STATX_GID).then_some(stx.stx_gid),
+        mode: returned(libc::STATX_MODE).then_some(u32::from(stx.stx_mode) & 0o7777),
+    }
+}
+
+/// `stat_at` for kernels without `statx`.
+// The types of the fields of `stat` vary between architectures
+#[allow(clippy::unnecessary_cast)]
+fn fstat_at(fd: BorrowedFd, name: &CStr, flags: libc::c_int) -> io::Result<Stat> {
+    let mut st = mem::MaybeUninit::<libc::stat>::uninit();
+    // SAFETY: `st` is large enough for a `stat` and only read once filled
+    let ret = unsafe { libc::fstatat(fd.as_raw_fd(), name.as_ptr(), st.as_mut_ptr(), flags) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fstatat succeeded, so it filled `st`
+    let st = unsafe { st.assume_init() };
+    Ok(Stat {
+        kind: FileKind::from_mode(st.st_mode as u32),
+        dev: st.st_dev as u64,
+        ino: st.st_ino as u64,
+        nlink: st.st_nlink as u64,
+        size: st.st_size as u64,
+        allocated: blocks_to_bytes(st.st_blocks as u64),
+        modified: system_time(st.st_mtime as i64, st.st_mtime_nsec as i64),
+        accessed: system_time(st.st_atime as i64, st.st_atime_nsec as i64),
+        changed: system_time(st.st_ctime as i64, st.st_ctime_nsec as i64),
+        uid: Some(st.st_uid),
+        gid: Some(st.st_gid),
+        mode: Some(st.st_mode as u32 & 0o7777),
+    })
+}
+
+/// The names in a directory, read in batches with `getdents64`.
+pub(super) struct DirEntries<'a> {
+    fd: BorrowedFd<'a>,
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+}
+
+impl<'a> DirEntries<'a> {
+    pub(super) fn new(fd: BorrowedFd<'a>) -> Self {
+        DirEntries {
+            fd,
+            buf: vec![0; DIRENT_BUF_SIZE],
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    /// The next name other than `.` and `..`, if any.
+    pub(super) fn next_name(&mut self) -> io::Result<Option<CString>> {
+        loop {
+            if self.pos >= self.len {
+                // SAFETY: the kernel writes at most `buf.len()` bytes to `buf`
+                let read = unsafe {
+                    libc::syscall(
+                        libc::SYS_getdents64,
+                        self.fd.as_raw_fd(),
+                        self.buf.as_mut_ptr(),
+                        self.buf.len(),
+                    )
+                };
+                if read < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if read == 0 {
+                    return Ok(None);
+                }
+                self.pos = 0;
+                self.len = read as usize;
+            }
+            // struct linux_dirent64 { u64 d_ino; i64 d_off; u16 d_reclen;
+            // u8 d_type; char d_name[]; }
+            let record = &self.buf[self.pos..self.len];
+            let reclen = u16::from_ne_bytes([record[16], record[17]]) as usize;
+            self.pos += reclen;
+            let name = CStr::from_bytes_until_nul(&record[19..reclen])
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            if name.to_bytes() != b"." && name.to_bytes() != b".." {
+                return Ok(Some(name.to_owned()));
+            }
+        }
+    }
+}
@@ -1,17 +1,77 @@
+pub use cancel::CancelToken;
+pub use filter::PathFilter;
+pub use progress::{Progress, ProgressSnapshot};
+pub use scan::{Scan, Strategy};
+pub use stat::FileKind;
+pub use stream::{Listing, PartialTree};
+pub use tree::{DirRef, FileRef, GitFilter, NodeId, Tree};
+
+use ignore_files::{IgnoreFiles, IGNORE_FILE_NAMES};
+use read_limit::ReadLimit;
+use stat::Stat;
+
 use std::{
+    collections::HashSet,
     ffi::{OsStr, OsString},
-    fmt, fs, io,
-    os::unix::fs::MetadataExt,
+    fmt, fs, io, mem,
     path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    time::SystemTime,
 };
 
+mod r#async;
+mod cancel;
+mod filter;
 mod fs_crossing;
-pub mod parallel;
-pub mod serial;
+mod ignore_files;
+#[cfg(target_os = "linux")]
+mod linux;
+mod parallel;
+mod progress;
+mod read_limit;
+mod scan;
+mod serial;
+mod stat;
+mod stream;
+mod tree;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
 
+#[derive(Debug, Clone)]
 pub struct Config {
     pub follow_symlinks: bool,
     pub same_filesystem: bool,
+    /// Which size is used for totals when a single one is shown.
+    pub size_kind: SizeKind,
+    /// How files with several hard links are counted in the totals.
+    pub hardlinks: Hardlinks,
+    /// Entries left out of the tree.
+    pub filter: PathFilter,
+    /// Whether the entries left out by `filter` are still summed into
+    /// `Sizes::excluded_size`, which costs walking excluded directories.
+    pub count_excluded: bool,
+    /// Whether to read `.gitignore`, `.ignore` and the global git excludes,
+    /// to tag the entries git would ignore. Ignored entries stay in the tree.
+    pub gitignore: bool,
+    /// Depth below which directories are summarised: their sizes are summed
+    /// up but their children aren't kept. The children of the root are at
+    /// depth 1.
+    pub max_depth: Option<usize>,
+    /// Metadata kept for each entry besides its sizes and modification time.
+    pub metadata: MetadataFields,
+    /// Updated during the walk, for another thread to follow it.
+    pub progress: Option<Arc<Progress>>,
+    /// Stops the walk early when cancelled.
+    pub cancel: Option<CancelToken>,
+    /// Receives the entries of each directory as soon as it is read, to show
+    /// the tree while it is being walked.
+    pub stream: Option<mpsc::Sender<Listing>>,
+    /// Threads of the parallel walker, one per CPU by default.
+    pub threads: Option<usize>,
+    /// Most directories read at the same time by the parallel and async
+    /// walkers. Unbounded by default for the parallel walker, which reads at
+    /// most one per thread anyway.
+    pub max_reads: Option<usize>,
 }
 
 impl Config {
@@ -19,30 +79,289 @@ impl Config {
         Config {
             follow_symlinks,
             same_filesystem,
+            size_kind: SizeKind::default(),
+            hardlinks: Hardlinks::default(),
+            filter: PathFilter::default(),
+            count_excluded: false,
+            gitignore: false,
+            max_depth: None,
+            metadata: MetadataFields::default(),
+            progress: None,
+            cancel: None,
+            stream: None,
+            threads: None,
+            max_reads: None,
         }
     }
 }
 
+/// How the size of a file is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SizeKind {
+    /// Length of the contents, as `ls -l` reports it.
+    #[default]
+    Apparent,
+    /// Blocks allocated on disk, as `du` reports it. Smaller than the
+    /// apparent size for sparse files, larger for tiny ones.
+    Allocated,
+}
+
+/// How the size of a file with several hard links is attributed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Hardlinks {
+    /// The first link found gets the whole size, the others nothing. Which
+    /// link comes first is unspecified for the parallel walker.
+    #[default]
+    CountOnce,
+    /// Every link gets `size / nlink`, including the links outside the tree.
+    Proportional,
+}
+
+/// Metadata the walkers only keep when asked to, none by default so that the
+/// tree stays small.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MetadataFields {
+    pub accessed: bool,
+    /// Time of the last status change.
+    pub changed: bool,
+    pub uid: bool,
+    pub gid: bool,
+    /// Permission bits.
+    pub mode: bool,
+    /// Keeps sockets, FIFOs and devices in the tree, as files tagged with
+    /// their type. They are left out otherwise.
+    pub file_type: bool,
+}
+
+impl MetadataFields {
+    /// The fields of `meta` to keep, None if there are none.
+    fn extra(&self, meta: &Stat) -> Option<Box<Extra>> {
+        if !(self.accessed || self.changed || self.uid || self.gid || self.mode) {
+            return None;
+        }
+        Some(Box::new(Extra {
+            accessed: meta.accessed.filter(|_| self.accessed),
+            changed: meta.changed.filter(|_| self.changed),
+            uid: meta.uid.filter(|_| self.uid),
+            gid: meta.gid.filter(|_| self.gid),
+            mode: meta.mode.filter(|_| self.mode),
+        }))
+    }
+}
+
+/// The metadata kept as asked by `Config::metadata`, each field None unless
+/// it was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Extra {
+    accessed: Option<SystemTime>,
+    changed: Option<SystemTime>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mode: Option<u32>,
+}
+
+/// `(dev, ino)`, which identifies a file across links to it.
+type FileId = (u64, u64);
+
 struct WalkContext {
     config: Config,
     root_fs: u64,
+    /// Multiply linked files already counted.
+    seen_inodes: Mutex<HashSet<FileId>>,
+    /// Multiply linked files already counted in the excluded sizes, apart
+    /// from `seen_inodes` so that the tree doesn't depend on what was
+    /// excluded.
+    excluded_inodes: Mutex<HashSet<FileId>>,
+    /// Present when `config.gitignore` is set.
+    ignore_files: Option<IgnoreFiles>,
+    /// Present when `config.max_reads` is set.
+    read_limit: Option<ReadLimit>,
+    /// The tree built so far: each directory gets its entries added once
+    /// read, and its sizes once walked.
+    tree: Mutex<Tree>,
 }
 
-#[derive(Debug)]
+/// Where a walk starts, shared by all the walkers.
+struct Root {
+    dir: Dir,
+    /// Canonical path of `dir`.
+    path: PathBuf,
+    context: WalkContext,
+    /// Errors met before the walk, reading the ignore files above `dir`.
+    errors: Vec<FileError>,
+}
+
+impl Root {
+    /// The walk of `path`, in which the hard links to `counted_links` are
+    /// already counted, and whose entries are all ignored by git if
+    /// `ignored`.
+    fn open(
+        path: &Path,
+        config: Config,
+        counted_links: HashSet<FileId>,
+        ignored: bool,
+    ) -> Result<Self, FileError> {
+        let root_can = path.canonicalize().map_err(|err| err.label(path))?;
+        let root_fs = if config.same_filesystem {
+            fs_crossing::device_num(&root_can).map_err(|err| err.label(path))?
+        } else {
+            0
+        };
+        let mut dir = Dir::root(&root_can, &config.metadata).map_err(|err| err.label(path))?;
+        let mut errors = Vec::new();
+        let ignore_files = config.gitignore.then(IgnoreFiles::new);
+        if let Some(ignore_files) = &ignore_files {
+            dir.ignored = ignored || ignore_files.load_ancestors(&root_can, |err| errors.push(err));
+        }
+        let tree = Tree::with_root(&mut dir);
+        let context = WalkContext::new(config, root_fs, tree, counted_links, ignore_files);
+        for _ in &errors {
+            context.count_error();
+        }
+        Ok(Root {
+            dir,
+            path: root_can,
+            context,
+            errors,
+        })
+    }
+}
+
+impl WalkContext {
+    /// A context for a walk building `tree`, in which the hard links to
+    /// `counted_links` are already counted.
+    fn new(
+        config: Config,
+        root_fs: u64,
+        tree: Tree,
+        counted_links: HashSet<FileId>,
+        ignore_files: Option<IgnoreFiles>,
+    ) -> Self {
+        WalkContext {
+            ignore_files,
+            read_limit: config.max_reads.map(ReadLimit::new),
+            config,
+            root_fs,
+            seen_inodes: Mutex::new(counted_links),
+            excluded_inodes: Mutex::new(HashSet::new()),
+            tree: Mutex::new(tree),
+        }
+    }
+
+    /// The tree of the walk, once `root` has been walked.
+    fn into_tree(self, root: &Dir) -> Tree {
+        let mut tree = self.tree.into_inner().unwrap();
+        let Place::At(id) = root.place else {
+            unreachable!("the root is in the tree")
+        };
+        tree.fill_dir(id, root);
+        tree.counted_links = self.seen_inodes.into_inner().unwrap();
+        tree.shrink_to_fit();
+        tree
+    }
+
+    /// How the entries of `dir`, found `depth` levels below the root, are
+    /// treated once read.
+    fn listed(&self, dir: &Dir, depth: usize) -> Listed {
+        if self.config.max_depth.is_some_and(|max| depth >= max) {
+            Listed::Summed
+        } else {
+            Listed::Kept {
+                ignored: dir.ignored,
+            }
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.config
+            .cancel
+            .as_ref()
+            .is_some_and(CancelToken::is_cancelled)
+    }
+
+    fn count_error(&self) {
+        if let Some(progress) = &self.config.progress {
+            progress.add_error();
+        }
+    }
+
+    /// Size and allocated size counted for a file, given its metadata, in
+    /// the excluded sizes if `excluded`.
+    fn attributed_size(&self, meta: &Stat, excluded: bool) -> (u64, u64) {
+        let nlink = meta.nlink;
+        if nlink <= 1 {
+            return (meta.size, meta.allocated);
+        }
+        match self.config.hardlinks {
+            Hardlinks::CountOnce => {
+                let seen = if excluded {
+                    &self.excluded_inodes
+                } else {
+                    &self.seen_inodes
+                };
+                let first = seen.lock().unwrap().insert((meta.dev, meta.ino));
+                if first {
+                    (meta.size, meta.allocated)
+                } else {
+                    (0, 0)
+                }
+            }
+            Hardlinks::Proportional => (meta.size / nlink, meta.allocated / nlink),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Sizes {
     pub files_size: u64,
     pub total_size: u64,
+    pub files_allocated: u64,
+    pub total_allocated: u64,
+    /// Number of files and directories below this one, at any depth.
+    pub item_count: u64,
+    /// Size of the entries below this one which were left out by the filter,
+    /// at any depth. Not part of the totals.
+    pub excluded_size: u64,
+    pub excluded_allocated: u64,
 }
 
-impl Default for Sizes {
-    fn default() -> Self {
-        Sizes {
-            files_size: 0,
-            total_size: 0,
+impl Sizes {
+    /// Size of the files directly inside the directory.
+    pub fn files(&self, kind: SizeKind) -> u64 {
+        match kind {
+            SizeKind::Apparent => self.files_size,
+            SizeKind::Allocated => self.files_allocated,
+        }
+    }
+
+    /// Size of everything below the directory.
+    pub fn total(&self, kind: SizeKind) -> u64 {
+        match kind {
+            SizeKind::Apparent => self.total_size,
+            SizeKind::Allocated => self.total_allocated,
+        }
+    }
+
+    pub fn excluded(&self, kind: SizeKind) -> u64 {
+        match kind {
+            SizeKind::Apparent => self.excluded_size,
+            SizeKind::Allocated => self.excluded_allocated,
         }
     }
+
+    /// Updates the totals after those of a subdirectory changed from `old`
+    /// to `new`.
+    fn replace_child(&mut self, old: &Sizes, new: &Sizes) {
+        self.total_size = self.total_size - old.total_size + new.total_size;
+        self.total_allocated = self.total_allocated - old.total_allocated + new.total_allocated;
+        self.item_count = self.item_count - old.item_count + new.item_count;
+        self.excluded_size = self.excluded_size - old.excluded_size + new.excluded_size;
+        self.excluded_allocated =
+            self.excluded_allocated - old.excluded_allocated + new.excluded_allocated;
+    }
 }
 
+#[derive(Clone, PartialEq, Eq)]
 pub struct CacheOsStr {
     os_str: Option<OsString>,
     string: String,
@@ -58,6 +377,25 @@ impl fmt::Debug for CacheOsStr {
 pub struct FileError {
     pub file: PathBuf,
     pub error: String,
+    pub kind: FileErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileErrorKind {
+    Io,
+    /// A directory which is also one of its own ancestors, reached through a
+    /// symbolic link. It is kept in the tree, but not walked.
+    SymlinkLoop,
+}
+
+impl FileError {
+    fn symlink_loop(file: PathBuf, ancestor: &Path) -> Self {
+        FileError {
+            error: format!("symlink loop back to {}", ancestor.display()),
+            file,
+            kind: FileErrorKind::SymlinkLoop,
+        }
+    }
 }
 
 impl fmt::Display for FileError {
@@ -66,6 +404,8 @@ impl fmt::Display for FileError {
     }
 }
 
+impl std::error::Error for FileError {}
+
 trait LabelError {
     fn take_label(self, file: PathBuf) -> FileError;
     fn label<P: AsRef<Path>>(self, file: P) -> FileError
@@ -81,6 +421,7 @@ impl LabelError for io::Error {
         FileError {
             file,
             error: self.to_string(),
+            kind: FileErrorKind::Io,
         }
     }
 }
@@ -119,11 +460,19 @@ impl AsRef<str> for CacheOsStr {
     }
 }
 
+/// A directory being walked. Its entries go to `WalkContext::tree` as soon as
+/// they are read, it only keeps its subdirectories until they are walked.
 pub struct Dir {
     name: CacheOsStr,
-    files: Vec<File>,
+    place: Place,
     dirs: Vec<Dir>,
     size: Sizes,
+    modified: Option<SystemTime>,
+    extra: Option<Box<Extra>>,
+    id: FileId,
+    ignored: bool,
+    summarized: bool,
+    incomplete: bool,
 }
 
 impl Clone for Dir {
@@ -136,86 +485,303 @@ impl fmt::Debug for Dir {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Dir")
             .field("name", &self.get_name())
-            .field("files", &format!("Vec<File>({})", self.files.len()))
             .field("dirs", &format!("Vec<Dir>({})", self.dirs.len()))
             .field("size", &self.size)
+            .field("modified", &self.modified)
             .finish()
     }
 }
 
 impl Dir {
-    fn new(name: OsString) -> Self {
+    fn new(name: OsString, modified: Option<SystemTime>, id: FileId) -> Self {
         Dir {
             name: name.into(),
-            files: Vec::new(),
+            place: Place::Unplaced,
             dirs: Vec::new(),
             size: Sizes::default(),
+            modified,
+            extra: None,
+            id,
+            ignored: false,
+            summarized: false,
+            incomplete: false,
         }
     }
 
+    /// The directory at the root of a walk, named after its full path.
+    fn root(path: &Path, metadata: &MetadataFields) -> io::Result<Self> {
+        let meta = Stat::from(fs::metadata(path)?);
+        let mut dir = Dir::new(
+            path.as_os_str().to_owned(),
+            meta.modified,
+            (meta.dev, meta.ino),
+        );
+        dir.extra = metadata.extra(&meta);
+        Ok(dir)
+    }
+
     pub fn get_name(&self) -> &str {
         self.name.as_ref()
     }
 
-    pub fn get_dirs(&self) -> &Vec<Dir> {
-        &self.dirs
+    pub fn get_os_name(&self) -> &OsStr {
+        self.name.as_ref()
     }
 
-    pub fn get_files(&self) -> &Vec<File> {
-        &self.files
+    /// A childless copy, for `Listing`.
+    fn shell(&self) -> Dir {
+        Dir {
+            name: self.name.clone(),
+            place: Place::Unplaced,
+            dirs: Vec::new(),
+            size: Sizes::default(),
+            modified: self.modified,
+            extra: self.extra.clone(),
+            id: self.id,
+            ignored: self.ignored,
+            summarized: false,
+            incomplete: false,
+        }
+    }
+
+    /// Adds the entries read from the directory to the tree, before its
+    /// subdirectories are walked. When they are only summed up, none are
+    /// added and the subdirectories are only walked for their sizes.
+    fn push_children(&mut self, children: Vec<Elem>, listed: Listed, context: &WalkContext) {
+        let count_once = context.config.hardlinks == Hardlinks::CountOnce;
+        let mut files = Vec::new();
+        let mut summed_links = Vec::new();
+        for child in children {
+            match child {
+                Elem::Dir(d) => {
+                    self.size.item_count += 1;
+                    self.dirs.push(d);
+                }
+                Elem::File(f) => {
+                    self.size.item_count += 1;
+                    self.size.files_size += f.size;
+                    self.size.files_allocated += f.allocated;
+                    if listed != Listed::Summed {
+                        files.push(f);
+                    } else if let Some(id) = f.counted_link().filter(|_| count_once) {
+                        summed_links.push(id);
+                    }
+                }
+                Elem::Excluded(usage, incomplete) => {
+                    self.size.excluded_size += usage.total_size;
+                    self.size.excluded_allocated += usage.total_allocated;
+                    self.incomplete |= incomplete;
+                }
+            }
+        }
+        self.summarized = listed == Listed::Summed && self.size.item_count > 0;
+        match (listed, self.place) {
+            (Listed::Kept { .. }, Place::At(id)) => {
+                context
+                    .tree
+                    .lock()
+                    .unwrap()
+                    .adopt(id, &mut self.dirs, &files);
+            }
+            (Listed::Summed, Place::At(into) | Place::Below(into)) => {
+                for dir in &mut self.dirs {
+                    dir.place = Place::Below(into);
+                }
+                if !summed_links.is_empty() {
+                    context
+                        .tree
+                        .lock()
+                        .unwrap()
+                        .add_summed_links(into, summed_links);
+                }
+            }
+            _ => unreachable!("directories are placed before being read"),
+        }
     }
 
-    pub fn get_size(&self) -> &Sizes {
-        &self.size
+    /// Computes the totals of the directory once all its subdirectories have
+    /// been walked, and stores theirs in the tree before dropping them.
+    fn sum_children(&mut self, context: &WalkContext) {
+        self.size.total_size =
+            self.size.files_size + self.dirs.iter().map(|d| d.size.total_size).sum::<u64>();
+        self.size.total_allocated = self.size.files_allocated
+            + self
+                .dirs
+                .iter()
+                .map(|d| d.size.total_allocated)
+                .sum::<u64>();
+        self.size.item_count += self.dirs.iter().map(|d| d.size.item_count).sum::<u64>();
+        self.size.excluded_size += self.dirs.iter().map(|d| d.size.excluded_size).sum::<u64>();
+        self.size.excluded_allocated += self
+            .dirs
+            .iter()
+            .map(|d| d.size.excluded_allocated)
+            .sum::<u64>();
+        self.incomplete |= self.dirs.iter().any(|d| d.incomplete);
+        let walked = mem::take(&mut self.dirs);
+        if walked.iter().any(|d| matches!(d.place, Place::At(_))) {
+            let mut tree = context.tree.lock().unwrap();
+            for d in &walked {
+                if let Place::At(id) = d.place {
+                    tree.fill_dir(id, d);
+                }
+            }
+        }
     }
 }
 
 // TODO: Open and close fd
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct File {
     name: CacheOsStr,
+    /// Size counted in the totals, which is only a share of the real size
+    /// for hard links.
     size: u64,
+    allocated: u64,
+    modified: Option<SystemTime>,
+    /// `FileKind::File`, or what else the file is when it isn't a regular
+    /// one
+    kind: FileKind,
+    extra: Option<Box<Extra>>,
+    hardlink: Option<Box<Hardlink>>,
+    ignored: bool,
+}
+
+/// A file with more than one hard link, which shares its data with the other
+/// links to the same inode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hardlink {
+    pub nlink: u64,
+    /// Size of the inode, of which the file is only attributed a share
+    pub size: u64,
+    pub allocated: u64,
+    id: FileId,
+}
+
+/// Where a directory being walked goes in the tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Place {
+    /// Not yet, until its parent has been read
+    Unplaced,
+    At(NodeId),
+    /// Nowhere, as it lies below the given summarised directory
+    Below(NodeId),
 }
 
 impl File {
-    fn new(name: OsString, size: u64) -> Self {
+    fn new(name: OsString, size: u64, allocated: u64, modified: Option<SystemTime>) -> Self {
         File {
             name: name.into(),
             size,
+            allocated,
+            modified,
+            kind: FileKind::File,
+            extra: None,
+            hardlink: None,
+            ignored: false,
         }
     }
 
-    fn get_name(&self) -> &str {
+    pub fn get_name(&self) -> &str {
         self.name.as_ref()
     }
 
-    fn get_size(&self) -> u64 {
-        self.size
+    pub fn get_size_as(&self, kind: SizeKind) -> u64 {
+        match kind {
+            SizeKind::Apparent => self.size,
+            SizeKind::Allocated => self.allocated,
+        }
+    }
+
+    /// The inode of the file, if it is the link given the whole size of the
+    /// inode, as the first one found with `Hardlinks::CountOnce`. Links to
+    /// empty inodes don't matter and are left out.
+    fn counted_link(&self) -> Option<FileId> {
+        let hardlink = self.hardlink.as_ref()?;
+        let whole = self.size == hardlink.size && self.allocated == hardlink.allocated;
+        (whole && self.size + self.allocated > 0).then_some(hardlink.id)
+    }
+
+    /// A copy for `Listing`, File isn't Clone so the tree can't be copied
+    /// by mistake.
+    fn copy(&self) -> File {
+        File {
+            name: self.name.clone(),
+            size: self.size,
+            allocated: self.allocated,
+            modified: self.modified,
+            kind: self.kind,
+            extra: self.extra.clone(),
+            hardlink: self.hardlink.clone(),
+            ignored: self.ignored,
+        }
     }
 }
 
+/// The directories above the one being walked, innermost first, to detect
+/// symlink loops.
+struct Ancestors<'a> {
+    id: FileId,
+    path: &'a Path,
+    parent: Option<&'a Ancestors<'a>>,
+}
+
+impl Ancestors<'_> {
+    /// Path of the ancestor with the given id, if any.
+    fn find(&self, id: FileId) -> Option<&Path> {
+        let mut ancestor = Some(self);
+        while let Some(a) = ancestor {
+            if a.id == id {
+                return Some(a.path);
+            }
+            ancestor = a.parent;
+        }
+        None
+    }
+}
+
+/// What becomes of the entries of a directory once read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Listed {
+    /// They are kept in the tree, tagged as ignored by git if `ignored`, the
+    /// directory itself is.
+    Kept { ignored: bool },
+    /// The directory lies at `Config::max_depth` or below, so they are only
+    /// summed up, without being tagged by git nor streamed one by one.
+    Summed,
+}
+
 #[derive(Debug)]
 enum Elem {
     File(File),
     Dir(Dir),
+    /// Entry left out by the filter, summed up for `Sizes::excluded_size`,
+    /// and whether the walk was cancelled before it was fully summed up.
+    Excluded(Sizes, bool),
 }
 
-pub fn print_tree(root: &Dir, indent: u32) {
+pub fn print_tree(root: DirRef, indent: u32, filter: GitFilter) {
     let indent_str = " ".repeat(indent as usize);
     println!("{}{}", indent_str, root.get_name());
-    for file in root.files.iter() {
+    for file in root.get_files().filter(|f| filter.keeps_file(*f)) {
         println!("{} {}", indent_str, file.get_name());
     }
-    for dir in root.dirs.iter() {
-        print_tree(dir, indent + 1);
+    for dir in root.get_dirs().filter(|d| filter.keeps_dir(*d)) {
+        print_tree(dir, indent + 1, filter);
     }
 }
 
+/// Reads the entries of the directory at `path`.
 fn read_dir_entry<F: FnMut(FileError)>(
     path: &Path,
+    listed: Listed,
     context: &WalkContext,
     mut err_collect: F,
 ) -> Vec<Elem> {
+    let mut err_collect = |err| {
+        context.count_error();
+        err_collect(err)
+    };
     let read_dir = match fs::read_dir(path) {
         Ok(rd) => rd,
         Err(err) => {
@@ -223,32 +789,250 @@ fn read_dir_entry<F: FnMut(FileError)>(
             return Vec::new();
         }
     };
-    read_dir
-        .map(|res| {
-            res.map_err(|err| err.label(path)).and_then(|entry| {
-                let meta = entry.metadata().map_err(|err| err.label(path))?;
-                if context.config.same_filesystem {
-                    if meta.dev() != context.root_fs {
-                        return Ok(None);
-                    }
+    let mut elems = Vec::new();
+    for res in read_dir {
+        let elem = res
+            .map_err(|err| err.label(path))
+            .and_then(|entry| read_entry(&entry, path, context, &mut err_collect));
+        match elem {
+            Ok(Some(elem)) => elems.push(elem),
+            Ok(None) => {}
+            Err(err) => err_collect(err),
+        }
+    }
+    finish_listing(path, listed, &mut elems, context, &mut err_collect);
+    elems
+}
+
+/// Tags the entries read from the directory at `path` which git ignores, and
+/// reports them to whoever follows the walk. `err_collect` must count errors.
+fn finish_listing(
+    path: &Path,
+    listed: Listed,
+    elems: &mut [Elem],
+    context: &WalkContext,
+    err_collect: &mut dyn FnMut(FileError),
+) {
+    if let (Some(ignore_files), Listed::Kept { ignored }) = (&context.ignore_files, listed) {
+        let has_ignore_file = elems
+            .iter()
+            .any(|elem| matches!(elem, Elem::File(f) if IGNORE_FILE_NAMES.contains(&f.get_name())));
+        if has_ignore_file && !ignored {
+            ignore_files.load(path, &mut *err_collect);
+        }
+        for elem in elems.iter_mut() {
+            match elem {
+                Elem::Dir(d) => {
+                    d.ignored = ignored
+                        || ignore_files.is_ignored(&path.join::<&OsStr>(d.name.as_ref()), true)
                 }
-                let mut file_type = meta.file_type();
-                if file_type.is_symlink() && context.config.follow_symlinks {
-                    file_type = fs::metadata(entry.path())
-                        .map_err(|err| err.label(path))?
-                        .file_type();
+                Elem::File(f) => {
+                    f.ignored = ignored
+                        || ignore_files.is_ignored(&path.join::<&OsStr>(f.name.as_ref()), false)
                 }
+                Elem::Excluded(..) => {}
+            }
+        }
+    }
 
-                let elem = if file_type.is_dir() {
-                    Some(Elem::Dir(Dir::new(entry.file_name())))
-                } else if file_type.is_file() || file_type.is_symlink() {
-                    Some(Elem::File(File::new(entry.file_name(), meta.len())))
-                } else {
-                    None
-                };
-                Ok(elem)
+    if let Some(progress) = &context.config.progress {
+        let bytes = elems
+            .iter()
+            .map(|elem| match elem {
+                Elem::File(f) => f.get_size_as(context.config.size_kind),
+                _ => 0,
             })
-        })
-        .filter_map(|res| res.map_err(&mut err_collect).ok().flatten())
-        .collect()
+            .sum();
+        progress.add_dir(path, elems.len() as u64, bytes);
+    }
+    if let Some(stream) = &context.config.stream {
+        let summed = listed == Listed::Summed;
+        let mut listing = Listing {
+            path: path.to_owned(),
+            files: Vec::new(),
+            dirs: Vec::new(),
+            size: Sizes::default(),
+            summed,
+        };
+        let size = &mut listing.size;
+        for elem in elems.iter() {
+            match elem {
+                Elem::File(f) => {
+                    size.files_size += f.size;
+                    size.files_allocated += f.allocated;
+                    size.item_count += 1;
+                    if !summed {
+                        listing.files.push(f.copy());
+                    }
+                }
+                Elem::Dir(d) => {
+                    size.item_count += 1;
+                    if !summed {
+                        listing.dirs.push(d.shell());
+                    }
+                }
+                Elem::Excluded(usage, _) => {
+                    size.excluded_size += usage.total_size;
+                    size.excluded_allocated += usage.total_allocated;
+                }
+            }
+        }
+        size.total_size = size.files_size;
+        size.total_allocated = size.files_allocated;
+        // Nobody may be listening any more, which is fine
+        let _ = stream.send(listing);
+    }
+}
+
+fn read_entry(
+    entry: &fs::DirEntry,
+    path: &Path,
+    context: &WalkContext,
+    err_collect: &mut dyn FnMut(FileError),
+) -> Result<Option<Elem>, FileError> {
+    let meta = entry.metadata().map_err(|err| err.label(path))?;
+    build_entry(
+        entry.file_name(),
+        path,
+        meta.into(),
+        || fs::metadata(entry.path()).map(Stat::from),
+        context,
+        err_collect,
+    )
+}
+
+/// Makes the node for the entry `name` of the directory at `path`, given its
+/// metadata and a way to get that of its target if it is a symbolic link.
+/// Entries of other kinds than files, directories and symbolic links are left
+/// out.
+fn build_entry(
+    name: OsString,
+    path: &Path,
+    meta: Stat,
+    target: impl FnOnce() -> io::Result<Stat>,
+    context: &WalkContext,
+    err_collect: &mut dyn FnMut(FileError),
+) -> Result<Option<Elem>, FileError> {
+    if context.config.same_filesystem && meta.dev != context.root_fs {
+        return Ok(None);
+    }
+    let mut kind = meta.kind;
+    let mut id = (meta.dev, meta.ino);
+    if kind == FileKind::Symlink && context.config.follow_symlinks {
+        let target = target().map_err(|err| err.label(path))?;
+        kind = target.kind;
+        id = (target.dev, target.ino);
+    }
+
+    if !context.config.filter.is_empty()
+        && context
+            .config
+            .filter
+            .excludes(&path.join(&name), kind == FileKind::Dir)
+    {
+        if !context.config.count_excluded {
+            return Ok(None);
+        }
+        let (usage, complete) = if kind == FileKind::Dir {
+            dir_usage(&path.join(&name), context, err_collect)
+        } else {
+            let (size, allocated) = context.attributed_size(&meta, true);
+            let usage = Sizes {
+                total_size: size,
+                total_allocated: allocated,
+                ..Sizes::default()
+            };
+            (usage, true)
+        };
+        return Ok(Some(Elem::Excluded(usage, !complete)));
+    }
+
+    let metadata = &context.config.metadata;
+    let elem = match kind {
+        FileKind::Dir => {
+            let mut dir = Dir::new(name, meta.modified, id);
+            dir.extra = metadata.extra(&meta);
+            Some(Elem::Dir(dir))
+        }
+        FileKind::File | FileKind::Symlink => Some(Elem::File(new_file(name, meta, kind, context))),
+        _ if kind.is_special() && metadata.file_type => {
+            Some(Elem::File(new_file(name, meta, kind, context)))
+        }
+        _ => None,
+    };
+    Ok(elem)
+}
+
+/// A file of the given kind, which is that of `meta` unless it is the target
+/// of a symbolic link.
+fn new_file(name: OsString, meta: Stat, kind: FileKind, context: &WalkContext) -> File {
+    let (size, allocated) = context.attributed_size(&meta, false);
+    let mut file = File::new(name, size, allocated, meta.modified);
+    file.kind = kind;
+    file.extra = context.config.metadata.extra(&meta);
+    if meta.nlink > 1 {
+        file.hardlink = Some(Box::new(Hardlink {
+            nlink: meta.nlink,
+            size: meta.size,
+            allocated: meta.allocated,
+            id: (meta.dev, meta.ino),
+        }));
+    }
+    file
+}
+
+/// Sums up everything below `path` without building any node, in
+/// `total_size`, `total_allocated` and `item_count`. Symbolic links are never
+/// followed and filters don't apply. Like the walkers, stops reading
+/// directories once the walk is cancelled, and then returns false along with
+/// what it found so far.
+fn dir_usage(
+    path: &Path,
+    context: &WalkContext,
+    err_collect: &mut dyn FnMut(FileError),
+) -> (Sizes, bool) {
+    let mut usage = Sizes::default();
+    if context.is_cancelled() {
+        return (usage, false);
+    }
+    let read_dir = match fs::read_dir(path) {
+        Ok(rd) => rd,
+        Err(err) => {
+            err_collect(err.label(path));
+            return (usage, true);
+        }
+    };
+    let mut complete = true;
+    for res in read_dir {
+        let entry = match res {
+            Ok(entry) => entry,
+            Err(err) => {
+                err_collect(err.label(path));
+                continue;
+            }
+        };
+        let meta = match entry.metadata() {
+            Ok(meta) => Stat::from(meta),
+            Err(err) => {
+                err_collect(err.label(path));
+                continue;
+            }
+        };
+        if context.config.same_filesystem && meta.dev != context.root_fs {
+            continue;
+        }
+        usage.item_count += 1;
+        if meta.kind == FileKind::Dir {
+            let (sub, sub_complete) = dir_usage(&entry.path(), context, err_collect);
+            usage.total_size += sub.total_size;
+            usage.total_allocated += sub.total_allocated;
+            usage.item_count += sub.item_count;
+            complete &= sub_complete;
+        } else {
+            let (size, allocated) = context.attributed_size(&meta, true);
+            usage.total_size += size;
+            usage.total_allocated += allocated;
+        }
+    }
+    (usage, complete)
 }
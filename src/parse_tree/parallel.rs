@@ -1,61 +1,68 @@
-use std::{collections::LinkedList, ffi::OsStr, io, path::Path};
+use std::{collections::LinkedList, ffi::OsStr, path::Path};
 
 use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
 
-use super::{fs_crossing, read_dir_entry, Config, Dir, Elem, FileError, LabelError, WalkContext};
+use super::{read_dir_entry, Ancestors, Dir, FileError, ReadLimit, Root, Tree, WalkContext};
 
-pub fn parse_tree<P: AsRef<Path>>(
-    path: P,
-    config: Config,
-) -> Result<(Dir, Vec<FileError>), FileError> {
-    let root_can = path
-        .as_ref()
-        .to_owned()
-        .canonicalize()
-        .map_err(|err| err.label(path.as_ref()))?;
-    let name = root_can.as_os_str().to_owned();
-
-    let context = WalkContext {
-        root_fs: if config.same_filesystem {
-            fs_crossing::device_num(&root_can).map_err(|err| err.label(path.as_ref()))?
-        } else {
-            0
-        },
-        config,
+/// Walks the tree on the current rayon pool, a directory per task.
+pub(super) fn walk(root: Root) -> (Tree, Vec<FileError>) {
+    let Root {
+        mut dir,
+        path,
+        context,
+        mut errors,
+    } = root;
+    let ancestors = Ancestors {
+        id: dir.id,
+        path: &path,
+        parent: None,
     };
-
-    let mut dir = Dir::new(name);
-    let errors = recurse_dir(&mut dir, &root_can.as_path(), &context);
-    Ok((dir, errors.into_iter().collect()))
+    errors.extend(recurse_dir(&mut dir, &path, &ancestors, 0, &context));
+    (context.into_tree(&dir), errors)
 }
 
-fn recurse_dir(dir: &mut Dir, path: &Path, context: &WalkContext) -> LinkedList<FileError> {
-    let mut errors = LinkedList::new();
-    let children = read_dir_entry(&path, context, |err| errors.push_back(err));
-    for child in children {
-        match child {
-            Elem::Dir(d) => dir.dirs.push(d),
-            Elem::File(f) => {
-                dir.size.files_size += f.size;
-                dir.files.push(f);
-            }
-        }
+/// Walks the children of `dir`, found at `path` and `depth` levels below the
+/// root. `ancestors` starts with `dir` itself.
+fn recurse_dir(
+    dir: &mut Dir,
+    path: &Path,
+    ancestors: &Ancestors,
+    depth: usize,
+    context: &WalkContext,
+) -> LinkedList<FileError> {
+    if context.is_cancelled() {
+        dir.incomplete = true;
+        return LinkedList::new();
     }
+    let mut errors = LinkedList::new();
+    let permit = context.read_limit.as_ref().map(ReadLimit::acquire);
+    let listed = context.listed(dir, depth);
+    let children = read_dir_entry(path, listed, context, |err| errors.push_back(err));
+    drop(permit);
+    dir.push_children(children, listed, context);
     let mut child_errors = dir
         .dirs
         .par_iter_mut()
-        .map(|d| recurse_dir(d, &path.to_owned().join::<&OsStr>(d.name.as_ref()), context))
-        .reduce(
-            || LinkedList::new(),
-            |mut lhs, mut rhs| {
-                lhs.append(&mut rhs);
-                lhs
-            },
-        );
+        .map(|d| {
+            let child_path = path.join::<&OsStr>(d.name.as_ref());
+            if let Some(ancestor) = ancestors.find(d.id) {
+                context.count_error();
+                return LinkedList::from([FileError::symlink_loop(child_path, ancestor)]);
+            }
+            let child_ancestors = Ancestors {
+                id: d.id,
+                path: &child_path,
+                parent: Some(ancestors),
+            };
+            recurse_dir(d, &child_path, &child_ancestors, depth + 1, context)
+        })
+        .reduce(LinkedList::new, |mut lhs, mut rhs| {
+            lhs.append(&mut rhs);
+            lhs
+        });
 
-    dir.size.total_size =
-        dir.size.files_size + dir.dirs.iter().map(|d| d.size.total_size).sum::<u64>();
-    if errors.len() > 0 {
+    dir.sum_children(context);
+    if !errors.is_empty() {
         child_errors.append(&mut errors);
     }
     child_errors
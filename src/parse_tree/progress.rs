@@ -0,0 +1,57 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Counters the walkers update as they go, for another thread to poll.
+#[derive(Debug, Default)]
+pub struct Progress {
+    entries: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+    current_dir: Mutex<PathBuf>,
+}
+
+/// The state of a walk at some point.
+#[derive(Debug, Clone, Default)]
+pub struct ProgressSnapshot {
+    /// Files and directories found so far
+    pub entries: u64,
+    /// Size of the files found so far, as given by `Config::size_kind`
+    pub bytes: u64,
+    pub errors: u64,
+    /// Directory read last
+    pub current_dir: PathBuf,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Progress::default()
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            entries: self.entries.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            current_dir: self.current_dir.lock().unwrap().clone(),
+        }
+    }
+
+    pub(super) fn add_dir(&self, path: &Path, entries: u64, bytes: u64) {
+        self.entries.fetch_add(entries, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        // Only a hint, so don't wait on other threads for it
+        if let Ok(mut current_dir) = self.current_dir.try_lock() {
+            current_dir.clear();
+            current_dir.push(path);
+        }
+    }
+
+    pub(super) fn add_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
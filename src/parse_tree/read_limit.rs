@@ -0,0 +1,38 @@
+use std::sync::{Condvar, Mutex};
+
+/// Bounds how many directories are read at the same time, across the threads
+/// of a walk, for disks which slow down under many concurrent requests.
+#[derive(Debug)]
+pub struct ReadLimit {
+    available: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// A read in progress, which lets another one start when dropped.
+pub struct ReadPermit<'a>(&'a ReadLimit);
+
+impl ReadLimit {
+    pub fn new(max_reads: usize) -> Self {
+        ReadLimit {
+            available: Mutex::new(max_reads.max(1)),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Waits until fewer than the maximum number of reads are in progress.
+    pub fn acquire(&self) -> ReadPermit<'_> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.freed.wait(available).unwrap();
+        }
+        *available -= 1;
+        ReadPermit(self)
+    }
+}
+
+impl Drop for ReadPermit<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock().unwrap() += 1;
+        self.0.freed.notify_one();
+    }
+}
@@ -0,0 +1,469 @@
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{mpsc, Arc},
+};
+
+use rayon::ThreadPoolBuilder;
+use tokio::task;
+
+#[cfg(target_os = "linux")]
+use super::linux;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use super::uring;
+use super::{
+    parallel, r#async, serial, CancelToken, Config, DirRef, FileError, FileErrorKind, FileId,
+    Hardlinks, LabelError, Listing, MetadataFields, PathFilter, Progress, Root, SizeKind, Tree,
+};
+
+/// How a scan walks the tree. All of them give the same tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    /// One directory after the other, on the calling thread
+    Serial,
+    /// Directories in parallel, on a rayon pool
+    #[default]
+    Parallel,
+    /// Directories in parallel, as tasks on a tokio runtime
+    Async,
+    /// Directories in parallel, on a rayon pool, reaching entries through
+    /// descriptors of their directory rather than full paths
+    #[cfg(target_os = "linux")]
+    Linux,
+    /// Like `Linux`, stating the entries of each directory in batches
+    /// through io_uring
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring,
+}
+
+impl Strategy {
+    pub const ALL: &'static [Strategy] = &[
+        Strategy::Serial,
+        Strategy::Parallel,
+        Strategy::Async,
+        #[cfg(target_os = "linux")]
+        Strategy::Linux,
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        Strategy::Uring,
+    ];
+}
+
+/// Directories read at the same time by the async walker, unless set with
+/// `Config::max_reads`.
+const DEFAULT_ASYNC_READS: usize = 64;
+
+/// A walk of the tree at some path, set up before being run:
+///
+/// ```ignore
+/// let (root, errors) = Scan::new(path)
+///     .follow_symlinks(true)
+///     .threads(4)
+///     .progress(progress)
+///     .strategy(Strategy::Parallel)
+///     .run()?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct Scan {
+    path: PathBuf,
+    config: Config,
+    strategy: Strategy,
+    /// Hard links counted before the scan, see `Scan::subtree`
+    counted_links: HashSet<FileId>,
+    /// Whether git ignores the root, as known from the tree it is loaded
+    /// into, see `Scan::subtree`
+    ignored: bool,
+}
+
+impl Scan {
+    /// A scan of `path`, staying on its filesystem and not following symbolic
+    /// links.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Scan::with_config(path, Config::new(false, true))
+    }
+
+    /// A scan of `path` set up by `config`, which the other methods then
+    /// change.
+    pub fn with_config(path: impl Into<PathBuf>, config: Config) -> Self {
+        Scan {
+            path: path.into(),
+            config,
+            strategy: Strategy::default(),
+            counted_links: HashSet::new(),
+            ignored: false,
+        }
+    }
+
+    /// A scan of the summarised directory `dir` set up by `config`, to load
+    /// it into its tree with `Tree::load_subtree`. The hard links counted
+    /// elsewhere in the tree aren't counted again, and the entries are
+    /// ignored by git if the directory is.
+    pub fn subtree(dir: DirRef, config: Config) -> Self {
+        Scan {
+            counted_links: dir.tree().links_counted_outside(dir.id()),
+            ignored: dir.is_ignored(),
+            ..Scan::with_config(dir.path(), config)
+        }
+    }
+
+    /// The settings of the scan so far.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Whether symbolic links to directories are walked as directories.
+    pub fn follow_symlinks(mut self, follow: bool) -> Self {
+        self.config.follow_symlinks = follow;
+        self
+    }
+
+    /// Whether to leave out the entries on other filesystems than the root.
+    pub fn same_filesystem(mut self, same_filesystem: bool) -> Self {
+        self.config.same_filesystem = same_filesystem;
+        self
+    }
+
+    pub fn size_kind(mut self, size_kind: SizeKind) -> Self {
+        self.config.size_kind = size_kind;
+        self
+    }
+
+    pub fn hardlinks(mut self, hardlinks: Hardlinks) -> Self {
+        self.config.hardlinks = hardlinks;
+        self
+    }
+
+    pub fn filter(mut self, filter: PathFilter) -> Self {
+        self.config.filter = filter;
+        self
+    }
+
+    /// Whether the entries left out by the filter are still summed up.
+    pub fn count_excluded(mut self, count_excluded: bool) -> Self {
+        self.config.count_excluded = count_excluded;
+        self
+    }
+
+    /// Whether to tag the entries git ignores.
+    pub fn gitignore(mut self, gitignore: bool) -> Self {
+        self.config.gitignore = gitignore;
+        self
+    }
+
+    /// Summarises the directories this many levels below the root, see
+    /// `Config::max_depth`.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.config.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn metadata(mut self, metadata: MetadataFields) -> Self {
+        self.config.metadata = metadata;
+        self
+    }
+
+    /// Threads of the walkers running on a rayon pool, one per CPU by
+    /// default.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.config.threads = Some(threads);
+        self
+    }
+
+    /// Most directories read at the same time, see `Config::max_reads`.
+    pub fn max_reads(mut self, max_reads: usize) -> Self {
+        self.config.max_reads = Some(max_reads);
+        self
+    }
+
+    pub fn progress(mut self, progress: Arc<Progress>) -> Self {
+        self.config.progress = Some(progress);
+        self
+    }
+
+    pub fn cancel(mut self, cancel: CancelToken) -> Self {
+        self.config.cancel = Some(cancel);
+        self
+    }
+
+    pub fn stream(mut self, stream: mpsc::Sender<Listing>) -> Self {
+        self.config.stream = Some(stream);
+        self
+    }
+
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Walks the tree, blocking until it is done.
+    pub fn run(self) -> Result<(Tree, Vec<FileError>), FileError> {
+        match self.strategy {
+            Strategy::Serial => Ok(serial::walk(Root::open(
+                &self.path,
+                self.config,
+                self.counted_links,
+                self.ignored,
+            )?)),
+            Strategy::Parallel => self.run_on_pool(parallel::walk),
+            #[cfg(target_os = "linux")]
+            Strategy::Linux => self.run_on_pool(linux::walk),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            Strategy::Uring => self.run_on_pool(uring::walk),
+            Strategy::Async => tokio::runtime::Builder::new_current_thread()
+                .build()
+                .map_err(|err| err.label(&self.path))?
+                .block_on(self.run_async()),
+        }
+    }
+
+    /// Runs a walker on a rayon pool of its own, so that the thread count can
+    /// be tuned to the disk without affecting the rest of the program.
+    fn run_on_pool(
+        self,
+        walk: fn(Root) -> (Tree, Vec<FileError>),
+    ) -> Result<(Tree, Vec<FileError>), FileError> {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(self.config.threads.unwrap_or(0))
+            .thread_name(|i| format!("walker-{}", i))
+            .build()
+            .map_err(|err| FileError {
+                file: self.path.clone(),
+                error: err.to_string(),
+                kind: FileErrorKind::Io,
+            })?;
+        let root = Root::open(&self.path, self.config, self.counted_links, self.ignored)?;
+        Ok(pool.install(|| walk(root)))
+    }
+
+    /// Walks the tree from a task on a tokio runtime, without blocking it.
+    pub async fn run_async(self) -> Result<(Tree, Vec<FileError>), FileError> {
+        match self.strategy {
+            Strategy::Async => {
+                let max_reads = self.config.max_reads.unwrap_or(DEFAULT_ASYNC_READS);
+                r#async::walk(
+                    self.path,
+                    self.config,
+                    self.counted_links,
+                    self.ignored,
+                    max_reads,
+                )
+                .await
+            }
+            _ => task::spawn_blocking(move || self.run())
+                .await
+                .expect("the walker panicked"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::{CString, OsStr},
+        fs,
+        os::unix::{
+            ffi::OsStrExt,
+            fs::{symlink, MetadataExt},
+        },
+        path::Path,
+    };
+
+    use super::*;
+    use crate::parse_tree::PartialTree;
+
+    /// Fills `root` with the entries the walkers are the most likely to
+    /// disagree on.
+    fn make_fixture(root: &Path) {
+        fs::create_dir_all(root.join("a/b/c")).unwrap();
+        fs::write(root.join("a/file"), "some contents").unwrap();
+        fs::hard_link(root.join("a/file"), root.join("a/b/c/link")).unwrap();
+        fs::hard_link(root.join("a/file"), root.join("other_link")).unwrap();
+        // A loop back to `a` once followed, and a link to a directory walked
+        // elsewhere
+        symlink("../..", root.join("a/b/c/loop")).unwrap();
+        symlink("a/b", root.join("to_b")).unwrap();
+        let fifo = CString::new(root.join("a/fifo").as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+        let non_utf8 = root.join(OsStr::from_bytes(b"non-utf8-\xff"));
+        fs::create_dir(&non_utf8).unwrap();
+        fs::write(non_utf8.join(OsStr::from_bytes(b"\xfe\xfd.txt")), "x").unwrap();
+        assert_eq!(fs::metadata(root.join("a/file")).unwrap().nlink(), 3);
+    }
+
+    #[test]
+    fn strategies_agree() {
+        let root = std::env::temp_dir().join(format!("rustdirstat-scan-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        make_fixture(&root);
+
+        let mut config = Config::new(false, true);
+        config.metadata = MetadataFields {
+            accessed: true,
+            changed: true,
+            uid: true,
+            gid: true,
+            mode: true,
+            file_type: true,
+        };
+        // Leaves out `a/b/c`, holding a link to `a/file`, and every file but
+        // `a/file` and the one in the non UTF-8 directory
+        let filter = PathFilter::new(&["file", "*.txt"], &["c/"]).unwrap();
+        // `a/b` is summarised at a max depth of 2
+        let cases = [
+            (false, None, false, 11),
+            (true, None, false, 17),
+            (false, Some(2), false, 11),
+            (false, None, true, 5),
+        ];
+        for (hardlinks, (follow_symlinks, max_depth, filtered, items)) in
+            [Hardlinks::CountOnce, Hardlinks::Proportional]
+                .into_iter()
+                .flat_map(|hardlinks| cases.map(|case| (hardlinks, case)))
+        {
+            config.hardlinks = hardlinks;
+            config.max_depth = max_depth;
+            config.filter = if filtered {
+                filter.clone()
+            } else {
+                PathFilter::default()
+            };
+            config.count_excluded = filtered;
+            let scan = |strategy| {
+                let (stream, listings) = mpsc::channel();
+                let (tree, mut errors) = Scan::with_config(&root, config.clone())
+                    .follow_symlinks(follow_symlinks)
+                    .threads(2)
+                    .stream(stream)
+                    .strategy(strategy)
+                    .run()
+                    .unwrap();
+                // The tree shown during the walk ends up as the walked one,
+                // save for the metadata of the root which has no listing
+                let mut partial = PartialTree::new();
+                for listing in listings {
+                    partial.add(listing);
+                }
+                assert_eq!(
+                    tree.root().first_difference_below(partial.root().unwrap()),
+                    None,
+                    "{:?} walker, streamed tree",
+                    strategy
+                );
+                errors.sort_by(|a, b| a.file.cmp(&b.file));
+                let errors: Vec<_> = errors.into_iter().map(|err| (err.file, err.kind)).collect();
+                (tree, errors)
+            };
+            let (first, first_errors) = scan(Strategy::Serial);
+            assert_eq!(first.root().get_size().item_count, items);
+            assert_eq!(first.root().get_size().excluded_size > 0, filtered);
+            assert_eq!(
+                first_errors
+                    .iter()
+                    .any(|(_, kind)| *kind == FileErrorKind::SymlinkLoop),
+                follow_symlinks
+            );
+            for &strategy in Strategy::ALL {
+                let (tree, errors) = scan(strategy);
+                let case = format!(
+                    "{:?} walker, {:?}, following symlinks: {}, max depth: {:?}, filtered: {}",
+                    strategy, hardlinks, follow_symlinks, max_depth, filtered
+                );
+                // Which link of a file is counted in full depends on the walk
+                // order when counting it once, which leaves only the totals
+                // to agree on
+                assert_eq!(first.root().get_size(), tree.root().get_size(), "{}", case);
+                if hardlinks == Hardlinks::Proportional {
+                    assert_eq!(first.root().first_difference(tree.root()), None, "{}", case);
+                }
+                assert_eq!(errors, first_errors, "{}", case);
+            }
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn loaded_subtrees_match_full_scan() {
+        let root = std::env::temp_dir().join(format!("rustdirstat-subtree-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        // The scanned directory lies in a repository, whose ignore file
+        // applies to it
+        let scanned = root.join("repo/scanned");
+        make_fixture(&scanned);
+        fs::create_dir(root.join("repo/.git")).unwrap();
+        fs::write(root.join("repo/.gitignore"), "*.log\nbuild/\n").unwrap();
+        fs::create_dir_all(scanned.join("a/build/out")).unwrap();
+        fs::write(scanned.join("a/build/out/bin"), "binary").unwrap();
+        fs::write(scanned.join("a/b/trace.log"), "log").unwrap();
+        // Links only found below summarised directories
+        fs::create_dir_all(scanned.join("d1")).unwrap();
+        fs::create_dir_all(scanned.join("d2/e")).unwrap();
+        fs::write(scanned.join("d1/shared"), "shared contents").unwrap();
+        fs::hard_link(scanned.join("d1/shared"), scanned.join("d2/e/shared")).unwrap();
+
+        let mut config = Config::new(false, true);
+        config.gitignore = true;
+        let scan = |config: &Config| {
+            Scan::with_config(&scanned, config.clone())
+                .strategy(Strategy::Serial)
+                .run()
+                .unwrap()
+                .0
+        };
+        let full = scan(&config);
+        assert!(full.root().holds_ignored());
+
+        for max_depth in [1, 2] {
+            config.max_depth = Some(max_depth);
+            let mut tree = scan(&config);
+            // Summarised directories are loaded in full, as the GUI does
+            config.max_depth = None;
+            loop {
+                let mut stack = vec![tree.root()];
+                let mut summarized = None;
+                while let Some(dir) = stack.pop() {
+                    if dir.is_summarized() {
+                        summarized = Some(dir);
+                        break;
+                    }
+                    stack.extend(dir.get_dirs());
+                }
+                let Some(dir) = summarized else { break };
+                let id = dir.id();
+                let (subtree, _) = Scan::subtree(dir, config.clone())
+                    .strategy(Strategy::Serial)
+                    .run()
+                    .unwrap();
+                tree.load_subtree(id, subtree);
+            }
+            assert_eq!(
+                full.root().first_difference(tree.root()),
+                None,
+                "max depth {}",
+                max_depth
+            );
+            assert_eq!(full.counted_links, tree.counted_links);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn cancelled_scans_are_incomplete() {
+        let root = std::env::temp_dir().join(format!("rustdirstat-cancel-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        make_fixture(&root);
+
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        for &strategy in Strategy::ALL {
+            let (tree, _) = Scan::new(&root)
+                .cancel(cancel.clone())
+                .strategy(strategy)
+                .run()
+                .unwrap();
+            assert!(tree.root().is_incomplete(), "{:?} walker", strategy);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
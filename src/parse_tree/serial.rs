@@ -1,53 +1,71 @@
-use std::{ffi::OsStr, io, path::Path};
+use std::ffi::OsStr;
+use std::mem;
 
-use crate::parse_tree::{fs_crossing, read_dir_entry};
+use crate::parse_tree::read_dir_entry;
 
-use super::{Config, Dir, Elem, FileError, WalkContext};
+use super::{Dir, FileError, Root, Tree};
 
-pub fn parse_tree<P: AsRef<Path>>(root: P, config: Config) -> io::Result<(Dir, Vec<FileError>)> {
-    let mut path = root.as_ref().canonicalize()?;
-    let name = path.as_os_str().to_owned();
+/// Walks the tree on the current thread, depth first.
+pub(super) fn walk(root: Root) -> (Tree, Vec<FileError>) {
+    let Root {
+        dir: mut root_dir,
+        mut path,
+        context,
+        mut errors,
+    } = root;
 
-    let context = WalkContext {
-        root_fs: if config.same_filesystem {
-            fs_crossing::device_num(&path)?
-        } else {
-            0
-        },
-        config,
-    };
-
-    let mut errors = Vec::new();
-    let root_dir = Dir::new(name);
-    let root_children = read_dir_entry(&path, &context, |err| errors.push(err));
+    if context.is_cancelled() {
+        root_dir.incomplete = true;
+        return (context.into_tree(&root_dir), errors);
+    }
+    let listed = context.listed(&root_dir, 0);
+    let root_children = read_dir_entry(&path, listed, &context, |err| errors.push(err));
+    root_dir.push_children(root_children, listed, &context);
 
-    let mut dir_stack = vec![(root_dir, root_children)];
-    while let Some((dir, children)) = dir_stack.last_mut() {
-        if let Some(child) = children.pop() {
-            match child {
-                Elem::Dir(d) => {
-                    path.push::<&OsStr>(&d.name.as_ref());
-                    // Err means filesystem boundary crossing.
-                    let grand_children = read_dir_entry(&path, &context, |err| errors.push(err));
-                    dir_stack.push((d, grand_children));
-                }
-                Elem::File(f) => {
-                    dir.size.files_size += f.size;
-                    dir.files.push(f);
-                }
-            }
-        } else {
+    // Each directory being walked, with the subdirectories it has left to walk
+    let mut dir_stack = vec![pending(root_dir)];
+    while let Some((_, subdirs)) = dir_stack.last_mut() {
+        let Some(mut d) = subdirs.pop() else {
             let (mut dir, _) = dir_stack.pop().unwrap();
-            dir.size.total_size += dir.size.files_size;
+            dir.sum_children(&context);
             path.pop();
-            if let Some((parent, _)) = dir_stack.last_mut() {
-                parent.size.total_size += dir.size.total_size;
-                parent.dirs.push(dir);
-            } else {
-                return Ok((dir, errors));
+            match dir_stack.last_mut() {
+                Some((parent, _)) => parent.dirs.push(dir),
+                None => return (context.into_tree(&dir), errors),
             }
+            continue;
+        };
+        path.push::<&OsStr>(d.name.as_ref());
+        // The stack holds the ancestors of d, the innermost last
+        if let Some(depth) = dir_stack.iter().position(|(a, _)| a.id == d.id) {
+            let ancestor = path.ancestors().nth(dir_stack.len() - depth).unwrap();
+            errors.push(FileError::symlink_loop(path.clone(), ancestor));
+            context.count_error();
+            path.pop();
+            dir_stack.last_mut().unwrap().0.dirs.push(d);
+            continue;
         }
+        if context.is_cancelled() {
+            // Kept empty and marked incomplete, like the other walkers do
+            // with the directories they don't read
+            path.pop();
+            d.incomplete = true;
+            dir_stack.last_mut().unwrap().0.dirs.push(d);
+            continue;
+        }
+        let listed = context.listed(&d, dir_stack.len());
+        let children = read_dir_entry(&path, listed, &context, |err| errors.push(err));
+        d.push_children(children, listed, &context);
+        dir_stack.push(pending(d));
     }
 
-    todo!()
+    unreachable!("the root is returned when popped")
+}
+
+/// Takes the subdirectories out of `dir`, to be walked in the order they
+/// were listed and pushed back once done.
+fn pending(mut dir: Dir) -> (Dir, Vec<Dir>) {
+    let mut subdirs = mem::take(&mut dir.dirs);
+    subdirs.reverse();
+    (dir, subdirs)
 }
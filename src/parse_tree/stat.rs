@@ -0,0 +1,126 @@
+use std::{
+    fs,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    time::{Duration, SystemTime},
+};
+
+/// What a file is, as far as the walkers care.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+    Other,
+}
+
+impl FileKind {
+    /// The kind given by the `S_IFMT` bits of a mode.
+    #[cfg(target_os = "linux")]
+    pub fn from_mode(mode: u32) -> Self {
+        match mode & libc::S_IFMT {
+            libc::S_IFREG => FileKind::File,
+            libc::S_IFDIR => FileKind::Dir,
+            libc::S_IFLNK => FileKind::Symlink,
+            libc::S_IFIFO => FileKind::Fifo,
+            libc::S_IFSOCK => FileKind::Socket,
+            libc::S_IFBLK => FileKind::BlockDevice,
+            libc::S_IFCHR => FileKind::CharDevice,
+            _ => FileKind::Other,
+        }
+    }
+
+    /// Sockets, FIFOs and devices, kept only with `MetadataFields::file_type`.
+    pub fn is_special(self) -> bool {
+        matches!(
+            self,
+            FileKind::Fifo | FileKind::Socket | FileKind::BlockDevice | FileKind::CharDevice
+        )
+    }
+}
+
+/// The metadata of a file used by the walkers, whichever call fetched it.
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+    pub kind: FileKind,
+    pub dev: u64,
+    pub ino: u64,
+    pub nlink: u64,
+    pub size: u64,
+    /// Space allocated on disk, in bytes
+    pub allocated: u64,
+    pub modified: Option<SystemTime>,
+    pub accessed: Option<SystemTime>,
+    /// Time of the last status change
+    pub changed: Option<SystemTime>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Permission bits, without the file type
+    pub mode: Option<u32>,
+}
+
+impl From<&fs::Metadata> for Stat {
+    fn from(meta: &fs::Metadata) -> Self {
+        let file_type = meta.file_type();
+        let kind = if file_type.is_dir() {
+            FileKind::Dir
+        } else if file_type.is_file() {
+            FileKind::File
+        } else if file_type.is_symlink() {
+            FileKind::Symlink
+        } else if file_type.is_fifo() {
+            FileKind::Fifo
+        } else if file_type.is_socket() {
+            FileKind::Socket
+        } else if file_type.is_block_device() {
+            FileKind::BlockDevice
+        } else if file_type.is_char_device() {
+            FileKind::CharDevice
+        } else {
+            FileKind::Other
+        };
+        Stat {
+            kind,
+            dev: meta.dev(),
+            ino: meta.ino(),
+            nlink: meta.nlink(),
+            size: meta.len(),
+            allocated: blocks_to_bytes(meta.blocks()),
+            modified: meta.modified().ok(),
+            accessed: meta.accessed().ok(),
+            changed: system_time(meta.ctime(), meta.ctime_nsec()),
+            uid: Some(meta.uid()),
+            gid: Some(meta.gid()),
+            mode: Some(meta.mode() & 0o7777),
+        }
+    }
+}
+
+impl From<fs::Metadata> for Stat {
+    fn from(meta: fs::Metadata) -> Self {
+        Stat::from(&meta)
+    }
+}
+
+/// Converts the allocated blocks found in `struct stat` or `struct statx`
+/// to bytes.
+pub fn blocks_to_bytes(blocks: u64) -> u64 {
+    // Always counted in 512 byte units, whatever the block size of the
+    // filesystem
+    blocks * 512
+}
+
+/// Converts a timestamp as found in `struct stat`.
+pub fn system_time(secs: i64, nsecs: i64) -> Option<SystemTime> {
+    let nsecs = Duration::from_nanos(u64::try_from(nsecs).ok()?);
+    if secs >= 0 {
+        SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64) + nsecs)
+    } else {
+        SystemTime::UNIX_EPOCH
+            .checked_sub(Duration::from_secs(secs.unsigned_abs()))?
+            .checked_add(nsecs)
+    }
+}
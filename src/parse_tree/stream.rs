@@ -0,0 +1,105 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use super::{
+    tree::{DirRef, NodeId, Tree},
+    Dir, File, Place, Sizes,
+};
+
+/// The entries of a directory, sent through `Config::stream` as soon as the
+/// directory has been read. Subdirectories come without children, which are
+/// sent in their own listings later on.
+#[derive(Debug)]
+pub struct Listing {
+    pub(super) path: PathBuf,
+    pub(super) files: Vec<File>,
+    pub(super) dirs: Vec<Dir>,
+    /// What the entries add to the sizes of the directory and those above
+    pub(super) size: Sizes,
+    /// Whether the directory lies at `Config::max_depth` or below, in which
+    /// case its entries are only summed up in `size`, and not sent
+    pub(super) summed: bool,
+}
+
+/// The tree of a walk in progress, put together from its listings.
+#[derive(Debug, Default)]
+pub struct PartialTree {
+    tree: Option<Tree>,
+    /// The directories of the tree whose listing hasn't come yet, by path,
+    /// and the summarised ones, which the listings below them add up to
+    pending: HashMap<PathBuf, NodeId>,
+}
+
+impl PartialTree {
+    pub fn new() -> Self {
+        PartialTree::default()
+    }
+
+    /// The tree so far, once the root has been read.
+    pub fn root(&self) -> Option<DirRef<'_>> {
+        self.tree.as_ref().map(Tree::root)
+    }
+
+    /// Adds the entries of a directory. Its parent must have been added
+    /// before, which the walkers guarantee since they read a directory
+    /// before its children.
+    pub fn add(&mut self, listing: Listing) {
+        let Listing {
+            path,
+            files,
+            mut dirs,
+            size: added,
+            summed,
+        } = listing;
+        let tree = self.tree.get_or_insert_with(|| {
+            let mut root = Dir::new(path.as_os_str().to_owned(), None, (0, 0));
+            let tree = Tree::with_root(&mut root);
+            self.pending.insert(path.clone(), tree.root().id());
+            tree
+        });
+        // The entries below a summarised directory are only counted in its
+        // sizes. It is the first one found on the way down, as those above
+        // have had their listing.
+        let found = if summed {
+            let mut prefix = PathBuf::new();
+            path.components().find_map(|component| {
+                prefix.push(component);
+                self.pending.get(&prefix).map(|&id| (id, prefix == path))
+            })
+        } else {
+            self.pending.remove(&path).map(|id| (id, true))
+        };
+        let Some((listed, exact)) = found else {
+            return;
+        };
+
+        // The totals of every directory from the listed one up to the root
+        // grow
+        let mut dir = Some(listed);
+        while let Some(id) = dir {
+            let size = tree.size_mut(id);
+            size.total_size += added.total_size;
+            size.total_allocated += added.total_allocated;
+            size.item_count += added.item_count;
+            size.excluded_size += added.excluded_size;
+            size.excluded_allocated += added.excluded_allocated;
+            dir = tree.parent_id(id);
+        }
+        if !exact {
+            return;
+        }
+        let size = tree.size_mut(listed);
+        size.files_size = added.files_size;
+        size.files_allocated = added.files_allocated;
+        if summed {
+            tree.set_summarized(listed, added.item_count > 0);
+            return;
+        }
+        // The subdirectories have no children yet, they come in later listings
+        tree.adopt(listed, &mut dirs, &files);
+        for dir in dirs {
+            if let Place::At(id) = dir.place {
+                self.pending.insert(path.join(dir.get_os_name()), id);
+            }
+        }
+    }
+}
@@ -0,0 +1,973 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::OsStr,
+    fmt, iter, mem,
+    ops::Range,
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{CacheOsStr, Dir, Extra, File, FileId, FileKind, Hardlink, Place, SizeKind, Sizes};
+
+/// Index of an entry in its `Tree`. The root is always the first one, and
+/// entries keep their id for as long as the tree lives, as loading a subtree
+/// only adds entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(u32);
+
+impl NodeId {
+    const ROOT: NodeId = NodeId(0);
+
+    fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+// Bits of `Tree::flags`
+const DIR: u8 = 1;
+const IGNORED: u8 = 1 << 1;
+const SUMMARIZED: u8 = 1 << 2;
+const INCOMPLETE: u8 = 1 << 3;
+/// The name isn't valid UTF-8, its lossy version is in `Tree::lossy_names`
+const NON_UTF8: u8 = 1 << 4;
+/// The top bits hold the kind of a file, see `kind_bits`
+const KIND_SHIFT: u8 = 5;
+
+/// `Tree::data` of a file which isn't a hard link
+const NO_DATA: u32 = u32::MAX;
+/// `Tree::modified` of an entry whose modification time is unknown
+const NO_TIME: i64 = i64::MIN;
+/// Missing value of `Tree::uids`, `gids` and `modes`
+const NO_ID: u32 = u32::MAX;
+
+/// The tree of a walk, stored compactly: each entry is an index into arrays
+/// holding one field each, and all the names share one buffer. The walkers
+/// add the entries of each directory as soon as it is read, and fill in its
+/// sizes once it has been walked.
+pub struct Tree {
+    // One element per entry, indexed by NodeId
+    parents: Vec<NodeId>,
+    flags: Vec<u8>,
+    /// Size of a file, unused for directories which have `DirData::size`
+    sizes: Vec<u64>,
+    allocated: Vec<u64>,
+    /// Nanoseconds since the epoch, or `NO_TIME`
+    modified: Vec<i64>,
+    /// Index into `dirs` for a directory, into `hardlinks` for a hard link
+    /// and `NO_DATA` otherwise
+    data: Vec<u32>,
+    /// End of each name in `names`, where the name of the next entry starts
+    name_ends: Vec<usize>,
+    names: Vec<u8>,
+    // Only filled when `Config::metadata` asks for them, see `push_field`
+    accessed: Vec<i64>,
+    changed: Vec<i64>,
+    uids: Vec<u32>,
+    gids: Vec<u32>,
+    modes: Vec<u32>,
+
+    dirs: Vec<DirData>,
+    hardlinks: Vec<Hardlink>,
+    lossy_names: HashMap<NodeId, Box<str>>,
+    /// Inodes of the hard links counted in the sizes, with
+    /// `Hardlinks::CountOnce`
+    pub(super) counted_links: HashSet<FileId>,
+    /// Those of `counted_links` first found below each summarised directory,
+    /// whose files aren't kept
+    summed_links: HashMap<NodeId, Vec<FileId>>,
+    /// Apparent and allocated size of what git ignores below each directory
+    /// holding any ignored entry, summed up when first asked for and dropped
+    /// whenever the tree changes
+    ignored_sizes: OnceLock<HashMap<NodeId, (u64, u64)>>,
+}
+
+/// What only directories have.
+#[derive(Debug, Clone, Copy)]
+struct DirData {
+    size: Sizes,
+    id: FileId,
+    /// The children are contiguous, subdirectories first, then files
+    first_child: u32,
+    n_dirs: u32,
+    n_files: u32,
+}
+
+impl Clone for Tree {
+    fn clone(&self) -> Self {
+        panic!("Don't Clone Me!")
+    }
+}
+
+impl fmt::Debug for Tree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tree")
+            .field("root", &self.root().get_name())
+            .field("nodes", &self.node_count())
+            .field("size", self.root().get_size())
+            .finish()
+    }
+}
+
+impl Tree {
+    /// A tree of `root` alone, without its children.
+    pub(super) fn with_root(root: &mut Dir) -> Self {
+        let mut tree = Tree {
+            parents: Vec::new(),
+            flags: Vec::new(),
+            sizes: Vec::new(),
+            allocated: Vec::new(),
+            modified: Vec::new(),
+            data: Vec::new(),
+            name_ends: Vec::new(),
+            names: Vec::new(),
+            accessed: Vec::new(),
+            changed: Vec::new(),
+            uids: Vec::new(),
+            gids: Vec::new(),
+            modes: Vec::new(),
+            dirs: Vec::new(),
+            hardlinks: Vec::new(),
+            lossy_names: HashMap::new(),
+            counted_links: HashSet::new(),
+            summed_links: HashMap::new(),
+            ignored_sizes: OnceLock::new(),
+        };
+        root.place = Place::At(tree.push_dir(NodeId::ROOT, root));
+        tree
+    }
+
+    pub fn root(&self) -> DirRef<'_> {
+        DirRef {
+            tree: self,
+            id: NodeId::ROOT,
+        }
+    }
+
+    /// Number of entries stored, which leaves out the children of summarised
+    /// directories.
+    pub fn node_count(&self) -> usize {
+        self.parents.len()
+    }
+
+    /// Bytes taken on the heap by the tree.
+    pub fn heap_size(&self) -> usize {
+        fn vec_size<T>(vec: &Vec<T>) -> usize {
+            vec.capacity() * mem::size_of::<T>()
+        }
+        vec_size(&self.parents)
+            + vec_size(&self.flags)
+            + vec_size(&self.sizes)
+            + vec_size(&self.allocated)
+            + vec_size(&self.modified)
+            + vec_size(&self.data)
+            + vec_size(&self.name_ends)
+            + vec_size(&self.names)
+            + vec_size(&self.accessed)
+            + vec_size(&self.changed)
+            + vec_size(&self.uids)
+            + vec_size(&self.gids)
+            + vec_size(&self.modes)
+            + vec_size(&self.dirs)
+            + vec_size(&self.hardlinks)
+            + self.lossy_names.capacity() * mem::size_of::<(NodeId, Box<str>)>()
+            + self
+                .lossy_names
+                .values()
+                .map(|name| name.len())
+                .sum::<usize>()
+            + self.counted_links.capacity() * mem::size_of::<FileId>()
+            + self.summed_links.capacity() * mem::size_of::<(NodeId, Vec<FileId>)>()
+            + self
+                .summed_links
+                .values()
+                .map(|links| links.capacity() * mem::size_of::<FileId>())
+                .sum::<usize>()
+    }
+
+    pub(super) fn shrink_to_fit(&mut self) {
+        self.parents.shrink_to_fit();
+        self.flags.shrink_to_fit();
+        self.sizes.shrink_to_fit();
+        self.allocated.shrink_to_fit();
+        self.modified.shrink_to_fit();
+        self.data.shrink_to_fit();
+        self.name_ends.shrink_to_fit();
+        self.names.shrink_to_fit();
+        self.accessed.shrink_to_fit();
+        self.changed.shrink_to_fit();
+        self.uids.shrink_to_fit();
+        self.gids.shrink_to_fit();
+        self.modes.shrink_to_fit();
+        self.dirs.shrink_to_fit();
+        self.hardlinks.shrink_to_fit();
+        self.lossy_names.shrink_to_fit();
+        self.counted_links.shrink_to_fit();
+        self.summed_links.shrink_to_fit();
+    }
+
+    fn next_id(&self) -> NodeId {
+        NodeId(u32::try_from(self.parents.len()).expect("fewer than 2^32 entries"))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn push_node(
+        &mut self,
+        parent: NodeId,
+        name: &OsStr,
+        lossy_name: Option<&str>,
+        mut flags: u8,
+        size: u64,
+        allocated: u64,
+        modified: i64,
+        extra: Extra,
+        data: u32,
+    ) -> NodeId {
+        let id = self.next_id();
+        self.ignored_sizes.take();
+        if let Some(lossy_name) = lossy_name {
+            flags |= NON_UTF8;
+            self.lossy_names.insert(id, lossy_name.into());
+        }
+        self.names.extend_from_slice(name.as_bytes());
+        self.name_ends.push(self.names.len());
+        self.parents.push(parent);
+        self.flags.push(flags);
+        self.sizes.push(size);
+        self.allocated.push(allocated);
+        self.modified.push(modified);
+        self.data.push(data);
+        push_field(&mut self.accessed, id, pack_time(extra.accessed), NO_TIME);
+        push_field(&mut self.changed, id, pack_time(extra.changed), NO_TIME);
+        push_field(&mut self.uids, id, extra.uid.unwrap_or(NO_ID), NO_ID);
+        push_field(&mut self.gids, id, extra.gid.unwrap_or(NO_ID), NO_ID);
+        push_field(&mut self.modes, id, extra.mode.unwrap_or(NO_ID), NO_ID);
+        id
+    }
+
+    /// Adds `dir`, without its children.
+    fn push_dir(&mut self, parent: NodeId, dir: &Dir) -> NodeId {
+        let data = u32::try_from(self.dirs.len()).expect("fewer than 2^32 directories");
+        self.dirs.push(DirData {
+            size: dir.size,
+            id: dir.id,
+            first_child: 0,
+            n_dirs: 0,
+            n_files: 0,
+        });
+        let mut flags = DIR;
+        for (set, flag) in [
+            (dir.ignored, IGNORED),
+            (dir.summarized, SUMMARIZED),
+            (dir.incomplete, INCOMPLETE),
+        ] {
+            if set {
+                flags |= flag;
+            }
+        }
+        let (name, lossy_name) = split_name(&dir.name);
+        self.push_node(
+            parent,
+            name,
+            lossy_name,
+            flags,
+            0,
+            0,
+            pack_time(dir.modified),
+            dir.extra.as_deref().copied().unwrap_or_default(),
+            data,
+        )
+    }
+
+    fn push_file(&mut self, parent: NodeId, file: &File) -> NodeId {
+        let data = match &file.hardlink {
+            Some(hardlink) => {
+                self.hardlinks.push((**hardlink).clone());
+                u32::try_from(self.hardlinks.len() - 1).expect("fewer than 2^32 hard links")
+            }
+            None => NO_DATA,
+        };
+        let (name, lossy_name) = split_name(&file.name);
+        self.push_node(
+            parent,
+            name,
+            lossy_name,
+            kind_bits(file.kind) | if file.ignored { IGNORED } else { 0 },
+            file.size,
+            file.allocated,
+            pack_time(file.modified),
+            file.extra.as_deref().copied().unwrap_or_default(),
+            data,
+        )
+    }
+
+    /// Adds an entry of another tree, without its children.
+    fn push_copy(&mut self, parent: NodeId, src: &Tree, node: NodeId) -> NodeId {
+        let i = node.index();
+        let data = if src.flags[i] & DIR != 0 {
+            self.dirs.push(DirData {
+                first_child: 0,
+                n_dirs: 0,
+                n_files: 0,
+                ..src.dirs[src.data[i] as usize]
+            });
+            (self.dirs.len() - 1) as u32
+        } else if src.data[i] != NO_DATA {
+            self.hardlinks
+                .push(src.hardlinks[src.data[i] as usize].clone());
+            (self.hardlinks.len() - 1) as u32
+        } else {
+            NO_DATA
+        };
+        self.push_node(
+            parent,
+            src.os_name(node),
+            src.lossy_names.get(&node).map(|name| &**name),
+            src.flags[i] & !NON_UTF8,
+            src.sizes[i],
+            src.allocated[i],
+            src.modified[i],
+            src.extra(node),
+            data,
+        )
+    }
+
+    /// Adds the children of the directory `id`, which had none. The
+    /// subdirectories are told where they were added, for their own children
+    /// and sizes to be filled in later.
+    pub(super) fn adopt(&mut self, id: NodeId, dirs: &mut [Dir], files: &[File]) {
+        let first_child = self.next_id().0;
+        for dir in dirs.iter_mut() {
+            dir.place = Place::At(self.push_dir(id, dir));
+        }
+        for file in files {
+            self.push_file(id, file);
+        }
+        let data = self.dir_data_mut(id);
+        data.first_child = first_child;
+        data.n_dirs = dirs.len() as u32;
+        data.n_files = files.len() as u32;
+    }
+
+    /// Records hard links counted below the summarised directory `id`.
+    pub(super) fn add_summed_links(&mut self, id: NodeId, links: Vec<FileId>) {
+        self.summed_links.entry(id).or_default().extend(links);
+    }
+
+    /// The hard links counted outside the summarised directory `id`, which
+    /// a walk of it mustn't count again.
+    pub(super) fn links_counted_outside(&self, id: NodeId) -> HashSet<FileId> {
+        let mut links = self.counted_links.clone();
+        for link in self.summed_links.get(&id).into_iter().flatten() {
+            links.remove(link);
+        }
+        links
+    }
+
+    /// Sets the sizes and state of the directory `id` once `dir`, which it
+    /// was added for, has been walked.
+    pub(super) fn fill_dir(&mut self, id: NodeId, dir: &Dir) {
+        self.dir_data_mut(id).size = dir.size;
+        self.set_flag(id, SUMMARIZED, dir.summarized);
+        self.set_flag(id, INCOMPLETE, dir.incomplete);
+    }
+
+    /// Copies the descendants of `src` below the directory `id`, which had
+    /// no children, along with the hard links `summed_links` records below
+    /// the summarised ones.
+    fn graft(&mut self, id: NodeId, src: DirRef, mut summed_links: HashMap<NodeId, Vec<FileId>>) {
+        let mut queue = VecDeque::from([(id, src)]);
+        while let Some((id, src)) = queue.pop_front() {
+            if let Some(links) = summed_links.remove(&src.id) {
+                self.summed_links.insert(id, links);
+            }
+            let first_child = self.next_id().0;
+            for dir in src.get_dirs() {
+                queue.push_back((self.push_copy(id, src.tree, dir.id), dir));
+            }
+            for file in src.get_files() {
+                self.push_copy(id, src.tree, file.id);
+            }
+            let data = self.dir_data_mut(id);
+            data.first_child = first_child;
+            data.n_dirs = src.get_dirs().len() as u32;
+            data.n_files = src.get_files().len() as u32;
+        }
+    }
+
+    /// Replaces the summarised directory `id` by the root of `walked`, a walk
+    /// of the same directory made with `Scan::subtree`. The sizes of the
+    /// directories above are updated.
+    pub fn load_subtree(&mut self, id: NodeId, mut walked: Tree) {
+        self.summed_links.remove(&id);
+        self.counted_links
+            .extend(mem::take(&mut walked.counted_links));
+        let summed_links = mem::take(&mut walked.summed_links);
+        let walked = walked.root();
+        let mut old = self.dir_data(id).size;
+        self.dir_data_mut(id).size = *walked.get_size();
+        self.set_flag(id, SUMMARIZED, walked.is_summarized());
+        self.set_flag(id, INCOMPLETE, walked.is_incomplete());
+        self.graft(id, walked, summed_links);
+
+        let mut child = id;
+        while let Some(parent) = self.parent_id(child) {
+            let new = self.dir_data(child).size;
+            let parent_old = self.dir_data(parent).size;
+            self.dir_data_mut(parent).size.replace_child(&old, &new);
+            let incomplete = self.dir(parent).get_dirs().any(|d| d.is_incomplete());
+            self.set_flag(parent, INCOMPLETE, incomplete);
+            old = parent_old;
+            child = parent;
+        }
+    }
+
+    pub(super) fn parent_id(&self, id: NodeId) -> Option<NodeId> {
+        (id != NodeId::ROOT).then(|| self.parents[id.index()])
+    }
+
+    pub(super) fn size_mut(&mut self, id: NodeId) -> &mut Sizes {
+        &mut self.dir_data_mut(id).size
+    }
+
+    pub(super) fn set_summarized(&mut self, id: NodeId, summarized: bool) {
+        self.set_flag(id, SUMMARIZED, summarized);
+    }
+
+    fn dir(&self, id: NodeId) -> DirRef<'_> {
+        DirRef { tree: self, id }
+    }
+
+    fn dir_data(&self, id: NodeId) -> &DirData {
+        &self.dirs[self.data[id.index()] as usize]
+    }
+
+    fn dir_data_mut(&mut self, id: NodeId) -> &mut DirData {
+        self.ignored_sizes.take();
+        &mut self.dirs[self.data[id.index()] as usize]
+    }
+
+    fn ignored_sizes(&self) -> &HashMap<NodeId, (u64, u64)> {
+        self.ignored_sizes.get_or_init(|| {
+            let mut sizes: HashMap<NodeId, (u64, u64)> = HashMap::new();
+            // Children always come after their parent, so going backwards
+            // sums up each directory before adding it to its parent
+            for i in (1..self.parents.len()).rev() {
+                let id = NodeId(i as u32);
+                let ignored = if !self.has_flag(id, IGNORED) {
+                    sizes.get(&id).copied()
+                } else if self.has_flag(id, DIR) {
+                    let size = self.dir_data(id).size;
+                    Some((size.total_size, size.total_allocated))
+                } else {
+                    Some((self.sizes[i], self.allocated[i]))
+                };
+                if let Some((size, allocated)) = ignored {
+                    let parent = sizes.entry(self.parents[i]).or_default();
+                    parent.0 += size;
+                    parent.1 += allocated;
+                }
+            }
+            sizes
+        })
+    }
+
+    fn has_flag(&self, id: NodeId, flag: u8) -> bool {
+        self.flags[id.index()] & flag != 0
+    }
+
+    fn set_flag(&mut self, id: NodeId, flag: u8, set: bool) {
+        if set {
+            self.flags[id.index()] |= flag;
+        } else {
+            self.flags[id.index()] &= !flag;
+        }
+    }
+
+    fn os_name(&self, id: NodeId) -> &OsStr {
+        let start = match id.index() {
+            0 => 0,
+            i => self.name_ends[i - 1],
+        };
+        OsStr::from_bytes(&self.names[start..self.name_ends[id.index()]])
+    }
+
+    fn name(&self, id: NodeId) -> &str {
+        if self.has_flag(id, NON_UTF8) {
+            return &self.lossy_names[&id];
+        }
+        std::str::from_utf8(self.os_name(id).as_bytes())
+            .expect("names without the NON_UTF8 flag came from valid strings")
+    }
+
+    /// Absolute path of an entry, joining the names of its ancestors, as
+    /// the root is named after its full path.
+    fn path(&self, id: NodeId) -> PathBuf {
+        let ancestors: Vec<NodeId> = iter::successors(Some(id), |&id| self.parent_id(id)).collect();
+        ancestors.iter().rev().map(|&id| self.os_name(id)).collect()
+    }
+
+    fn modified(&self, id: NodeId) -> Option<SystemTime> {
+        unpack_time(self.modified[id.index()])
+    }
+
+    fn extra(&self, id: NodeId) -> Extra {
+        Extra {
+            accessed: get_field(&self.accessed, id, NO_TIME).and_then(unpack_time),
+            changed: get_field(&self.changed, id, NO_TIME).and_then(unpack_time),
+            uid: get_field(&self.uids, id, NO_ID),
+            gid: get_field(&self.gids, id, NO_ID),
+            mode: get_field(&self.modes, id, NO_ID),
+        }
+    }
+
+    /// The metadata `first_difference` compares, which leaves out the access
+    /// time as walking a tree updates that of its directories.
+    fn compared_extra(&self, id: NodeId) -> Extra {
+        Extra {
+            accessed: None,
+            ..self.extra(id)
+        }
+    }
+}
+
+/// The name as stored in the tree, and its lossy version if it isn't UTF-8.
+fn split_name(name: &CacheOsStr) -> (&OsStr, Option<&str>) {
+    (
+        name.as_ref(),
+        name.os_str.is_some().then_some(&*name.string),
+    )
+}
+
+/// Pushes the value of an optional field of the entry `id`. The array of the
+/// field stays empty until an entry has a value for it, and from then on holds
+/// one per entry, `missing` for those without.
+fn push_field<T: Copy + PartialEq>(values: &mut Vec<T>, id: NodeId, value: T, missing: T) {
+    if value != missing || !values.is_empty() {
+        values.resize(id.index(), missing);
+        values.push(value);
+    }
+}
+
+fn get_field<T: Copy + PartialEq>(values: &[T], id: NodeId, missing: T) -> Option<T> {
+    values
+        .get(id.index())
+        .copied()
+        .filter(|&value| value != missing)
+}
+
+/// The kind of a file packed into `Tree::flags`, zero for regular files and
+/// directories.
+fn kind_bits(kind: FileKind) -> u8 {
+    let bits = match kind {
+        FileKind::File | FileKind::Dir => 0,
+        FileKind::Symlink => 1,
+        FileKind::Fifo => 2,
+        FileKind::Socket => 3,
+        FileKind::BlockDevice => 4,
+        FileKind::CharDevice => 5,
+        FileKind::Other => 6,
+    };
+    bits << KIND_SHIFT
+}
+
+fn kind_from_bits(flags: u8) -> FileKind {
+    match flags >> KIND_SHIFT {
+        0 if flags & DIR != 0 => FileKind::Dir,
+        0 => FileKind::File,
+        1 => FileKind::Symlink,
+        2 => FileKind::Fifo,
+        3 => FileKind::Socket,
+        4 => FileKind::BlockDevice,
+        5 => FileKind::CharDevice,
+        _ => FileKind::Other,
+    }
+}
+
+fn unpack_time(nanos: i64) -> Option<SystemTime> {
+    match nanos {
+        NO_TIME => None,
+        nanos if nanos < 0 => UNIX_EPOCH.checked_sub(Duration::from_nanos(nanos.unsigned_abs())),
+        nanos => UNIX_EPOCH.checked_add(Duration::from_nanos(nanos as u64)),
+    }
+}
+
+/// Nanoseconds since the epoch, saturated to the ±292 years an i64 holds.
+fn pack_time(time: Option<SystemTime>) -> i64 {
+    let Some(time) = time else {
+        return NO_TIME;
+    };
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => i64::try_from(after.as_nanos()).unwrap_or(i64::MAX),
+        Err(before) => i64::try_from(before.duration().as_nanos())
+            .map_or(NO_TIME + 1, |nanos| -nanos)
+            .max(NO_TIME + 1),
+    }
+}
+
+/// A directory of a `Tree`.
+#[derive(Clone, Copy)]
+pub struct DirRef<'a> {
+    tree: &'a Tree,
+    id: NodeId,
+}
+
+impl<'a> DirRef<'a> {
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// The tree the directory belongs to.
+    pub fn tree(&self) -> &'a Tree {
+        self.tree
+    }
+
+    /// The directory containing this one, None for the root.
+    pub fn parent(&self) -> Option<DirRef<'a>> {
+        self.tree.parent_id(self.id).map(|id| self.tree.dir(id))
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.tree.path(self.id)
+    }
+
+    pub fn get_name(&self) -> &'a str {
+        self.tree.name(self.id)
+    }
+
+    pub fn get_os_name(&self) -> &'a OsStr {
+        self.tree.os_name(self.id)
+    }
+
+    pub fn get_dirs(&self) -> Children<'a, DirRef<'a>> {
+        let data = self.tree.dir_data(self.id);
+        Children {
+            tree: self.tree,
+            ids: data.first_child..data.first_child + data.n_dirs,
+            entry: |tree, id| DirRef { tree, id },
+        }
+    }
+
+    pub fn get_files(&self) -> Children<'a, FileRef<'a>> {
+        let data = self.tree.dir_data(self.id);
+        let first_file = data.first_child + data.n_dirs;
+        Children {
+            tree: self.tree,
+            ids: first_file..first_file + data.n_files,
+            entry: |tree, id| FileRef { tree, id },
+        }
+    }
+
+    pub fn get_size(&self) -> &'a Sizes {
+        &self.tree.dir_data(self.id).size
+    }
+
+    pub fn get_modified(&self) -> Option<SystemTime> {
+        self.tree.modified(self.id)
+    }
+
+    pub fn get_accessed(&self) -> Option<SystemTime> {
+        self.tree.extra(self.id).accessed
+    }
+
+    /// Time of the last status change.
+    pub fn get_changed(&self) -> Option<SystemTime> {
+        self.tree.extra(self.id).changed
+    }
+
+    pub fn get_uid(&self) -> Option<u32> {
+        self.tree.extra(self.id).uid
+    }
+
+    pub fn get_gid(&self) -> Option<u32> {
+        self.tree.extra(self.id).gid
+    }
+
+    /// Permission bits.
+    pub fn get_mode(&self) -> Option<u32> {
+        self.tree.extra(self.id).mode
+    }
+
+    /// Whether git ignores the directory, when `Config::gitignore` is set.
+    pub fn is_ignored(&self) -> bool {
+        self.tree.has_flag(self.id, IGNORED)
+    }
+
+    /// Whether the directory is ignored by git or holds entries which are.
+    pub fn holds_ignored(&self) -> bool {
+        self.is_ignored() || self.tree.ignored_sizes().contains_key(&self.id)
+    }
+
+    /// Size of the entries below the directory which git ignores, the whole
+    /// size of an ignored directory. Summarised directories only count if
+    /// they are ignored themselves.
+    pub fn ignored_size(&self, kind: SizeKind) -> u64 {
+        if self.is_ignored() {
+            return self.get_size().total(kind);
+        }
+        let (size, allocated) = self
+            .tree
+            .ignored_sizes()
+            .get(&self.id)
+            .copied()
+            .unwrap_or_default();
+        match kind {
+            SizeKind::Apparent => size,
+            SizeKind::Allocated => allocated,
+        }
+    }
+
+    /// Whether the children of the directory were dropped because of
+    /// `Config::max_depth`. Its sizes still account for them.
+    pub fn is_summarized(&self) -> bool {
+        self.tree.has_flag(self.id, SUMMARIZED)
+    }
+
+    /// Whether some directories below this one weren't read because the walk
+    /// was cancelled.
+    pub fn is_incomplete(&self) -> bool {
+        self.tree.has_flag(self.id, INCOMPLETE)
+    }
+
+    /// Path, relative to `self`, of the first entry which differs from the
+    /// other tree, regardless of the order of the children. The roots
+    /// themselves may have different names, and access times are left out
+    /// since walking the trees changes them.
+    pub fn first_difference(&self, other: DirRef) -> Option<PathBuf> {
+        self.diff_at(other, PathBuf::new(), true)
+    }
+
+    /// Like `first_difference`, but leaving out the metadata of both
+    /// directories themselves, which the root of a `PartialTree` lacks.
+    #[cfg(test)]
+    pub(super) fn first_difference_below(&self, other: DirRef) -> Option<PathBuf> {
+        self.diff_at(other, PathBuf::new(), false)
+    }
+
+    /// Compares the metadata of the directories themselves too if `own`.
+    fn diff_at(&self, other: DirRef, path: PathBuf, own: bool) -> Option<PathBuf> {
+        const COMPARED: u8 = IGNORED | SUMMARIZED | INCOMPLETE;
+        let own_differs = || {
+            self.get_modified() != other.get_modified()
+                || self.tree.compared_extra(self.id) != other.tree.compared_extra(other.id)
+                || self.tree.dir_data(self.id).id != other.tree.dir_data(other.id).id
+        };
+        if self.get_size() != other.get_size()
+            || (own && own_differs())
+            || self.tree.flags[self.id.index()] & COMPARED
+                != other.tree.flags[other.id.index()] & COMPARED
+            || self.get_files().len() != other.get_files().len()
+            || self.get_dirs().len() != other.get_dirs().len()
+        {
+            return Some(path);
+        }
+        let mut files: Vec<_> = self.get_files().collect();
+        let mut other_files: Vec<_> = other.get_files().collect();
+        files.sort_by_key(|f| f.get_name());
+        other_files.sort_by_key(|f| f.get_name());
+        for (file, other_file) in files.into_iter().zip(other_files) {
+            if !file.same_as(other_file) {
+                return Some(path.join(file.get_os_name()));
+            }
+        }
+        let mut dirs: Vec<_> = self.get_dirs().collect();
+        let mut other_dirs: Vec<_> = other.get_dirs().collect();
+        dirs.sort_by_key(|d| d.get_name());
+        other_dirs.sort_by_key(|d| d.get_name());
+        for (dir, other_dir) in dirs.into_iter().zip(other_dirs) {
+            let dir_path = path.join(dir.get_os_name());
+            if dir.get_os_name() != other_dir.get_os_name() {
+                return Some(dir_path);
+            }
+            if let Some(diff) = dir.diff_at(other_dir, dir_path, true) {
+                return Some(diff);
+            }
+        }
+        None
+    }
+}
+
+/// A file of a `Tree`.
+#[derive(Clone, Copy)]
+pub struct FileRef<'a> {
+    tree: &'a Tree,
+    id: NodeId,
+}
+
+impl<'a> FileRef<'a> {
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn get_name(&self) -> &'a str {
+        self.tree.name(self.id)
+    }
+
+    pub fn get_os_name(&self) -> &'a OsStr {
+        self.tree.os_name(self.id)
+    }
+
+    pub fn parent(&self) -> DirRef<'a> {
+        self.tree.dir(self.tree.parents[self.id.index()])
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.tree.path(self.id)
+    }
+
+    /// Size counted in the totals, which is only a share of the real size
+    /// for hard links.
+    pub fn get_size(&self) -> u64 {
+        self.tree.sizes[self.id.index()]
+    }
+
+    pub fn get_allocated(&self) -> u64 {
+        self.tree.allocated[self.id.index()]
+    }
+
+    pub fn get_size_as(&self, kind: SizeKind) -> u64 {
+        match kind {
+            SizeKind::Apparent => self.get_size(),
+            SizeKind::Allocated => self.get_allocated(),
+        }
+    }
+
+    pub fn get_modified(&self) -> Option<SystemTime> {
+        self.tree.modified(self.id)
+    }
+
+    pub fn get_accessed(&self) -> Option<SystemTime> {
+        self.tree.extra(self.id).accessed
+    }
+
+    /// Time of the last status change.
+    pub fn get_changed(&self) -> Option<SystemTime> {
+        self.tree.extra(self.id).changed
+    }
+
+    pub fn get_uid(&self) -> Option<u32> {
+        self.tree.extra(self.id).uid
+    }
+
+    pub fn get_gid(&self) -> Option<u32> {
+        self.tree.extra(self.id).gid
+    }
+
+    /// Permission bits.
+    pub fn get_mode(&self) -> Option<u32> {
+        self.tree.extra(self.id).mode
+    }
+
+    /// A regular file, a symbolic link which wasn't followed, or a special
+    /// file kept with `MetadataFields::file_type`.
+    pub fn get_kind(&self) -> FileKind {
+        kind_from_bits(self.tree.flags[self.id.index()])
+    }
+
+    pub fn get_hardlink(&self) -> Option<&'a Hardlink> {
+        match self.tree.data[self.id.index()] {
+            NO_DATA => None,
+            idx => Some(&self.tree.hardlinks[idx as usize]),
+        }
+    }
+
+    /// Whether git ignores the file, when `Config::gitignore` is set.
+    pub fn is_ignored(&self) -> bool {
+        self.tree.has_flag(self.id, IGNORED)
+    }
+
+    /// Whether both files have the same name and metadata, in any trees.
+    fn same_as(&self, other: FileRef) -> bool {
+        self.get_os_name() == other.get_os_name()
+            && self.get_size() == other.get_size()
+            && self.get_allocated() == other.get_allocated()
+            && self.get_modified() == other.get_modified()
+            && self.tree.compared_extra(self.id) == other.tree.compared_extra(other.id)
+            && self.get_kind() == other.get_kind()
+            && self.get_hardlink() == other.get_hardlink()
+            && self.is_ignored() == other.is_ignored()
+    }
+}
+
+/// The subdirectories or the files of a directory, in the order they were
+/// read.
+#[derive(Clone)]
+pub struct Children<'a, T> {
+    tree: &'a Tree,
+    ids: Range<u32>,
+    entry: fn(&'a Tree, NodeId) -> T,
+}
+
+impl<T> Children<'_, T> {
+    /// The child at `idx`, counting from the first one not yet iterated over.
+    pub fn get(&self, idx: usize) -> Option<T> {
+        let id = self.ids.start.checked_add(u32::try_from(idx).ok()?)?;
+        (id < self.ids.end).then(|| (self.entry)(self.tree, NodeId(id)))
+    }
+}
+
+impl<T> Iterator for Children<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.ids
+            .next()
+            .map(|id| (self.entry)(self.tree, NodeId(id)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.ids.size_hint()
+    }
+}
+
+impl<T> DoubleEndedIterator for Children<'_, T> {
+    fn next_back(&mut self) -> Option<T> {
+        self.ids
+            .next_back()
+            .map(|id| (self.entry)(self.tree, NodeId(id)))
+    }
+}
+
+impl<T> ExactSizeIterator for Children<'_, T> {}
+
+/// Which entries of a tree to show, telling apart those git ignores.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GitFilter {
+    #[default]
+    All,
+    /// Leaves out the ignored entries
+    Tracked,
+    /// Only keeps the ignored entries and the directories holding some
+    Ignored,
+}
+
+impl GitFilter {
+    pub fn keeps_dir(self, dir: DirRef) -> bool {
+        match self {
+            GitFilter::All => true,
+            GitFilter::Tracked => !dir.is_ignored(),
+            GitFilter::Ignored => dir.holds_ignored(),
+        }
+    }
+
+    pub fn keeps_file(self, file: FileRef) -> bool {
+        match self {
+            GitFilter::All => true,
+            GitFilter::Tracked => !file.is_ignored(),
+            GitFilter::Ignored => file.is_ignored(),
+        }
+    }
+
+    /// Size of the entries kept below `dir`.
+    pub fn dir_size(self, dir: DirRef, kind: SizeKind) -> u64 {
+        match self {
+            GitFilter::All => dir.get_size().total(kind),
+            GitFilter::Tracked => dir
+                .get_size()
+                .total(kind)
+                .saturating_sub(dir.ignored_size(kind)),
+            GitFilter::Ignored => dir.ignored_size(kind),
+        }
+    }
+}
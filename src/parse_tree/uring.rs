@@ -0,0 +1,192 @@
+use std::{
+    cell::RefCell,
+    ffi::{CString, OsString},
+    io, mem,
+    os::{
+        fd::{AsRawFd, BorrowedFd},
+        unix::ffi::OsStringExt,
+    },
+    path::Path,
+};
+
+use io_uring::{opcode, types, IoUring, Probe};
+
+use super::{
+    build_entry, finish_listing,
+    linux::{self, DirEntries, StatxOptions},
+    stat::Stat,
+    Elem, FileError, LabelError, Listed, Root, Tree, WalkContext,
+};
+
+/// Stat calls in flight at the same time on a thread's ring.
+const RING_ENTRIES: u32 = 256;
+
+thread_local! {
+    /// The ring of each walker thread, None if io_uring or its statx
+    /// operation isn't available.
+    static RING: RefCell<Option<IoUring>> = RefCell::new(statx_ring());
+}
+
+/// A new ring, if the kernel can stat through it. Kernels 5.1 to 5.5 have
+/// io_uring without statx.
+fn statx_ring() -> Option<IoUring> {
+    let ring = IoUring::new(RING_ENTRIES).ok()?;
+    let mut probe = Probe::new();
+    ring.submitter().register_probe(&mut probe).ok()?;
+    probe.is_supported(opcode::Statx::CODE).then_some(ring)
+}
+
+/// Walks the tree like `linux::walk`, but stats the entries of a directory
+/// in batches through io_uring rather than one system call at a time. The
+/// names are still read with `getdents64`, which io_uring can't do. Threads
+/// without a ring fall back to `fstatat`.
+pub(super) fn walk(root: Root) -> (Tree, Vec<FileError>) {
+    linux::walk_with(root, read_dir_at)
+}
+
+fn read_dir_at(
+    fd: BorrowedFd,
+    path: &Path,
+    listed: Listed,
+    context: &WalkContext,
+    err_collect: &mut dyn FnMut(FileError),
+) -> Vec<Elem> {
+    let mut err_collect = |err| {
+        context.count_error();
+        err_collect(err)
+    };
+    let mut names = Vec::new();
+    let mut entries = DirEntries::new(fd);
+    loop {
+        match entries.next_name() {
+            Ok(Some(name)) => names.push(name),
+            Ok(None) => break,
+            Err(err) => {
+                err_collect(err.label(path));
+                break;
+            }
+        }
+    }
+
+    let options = linux::statx_options(fd, &context.config.metadata);
+    let mut elems = Vec::new();
+    for (name, meta) in stat_batch(fd, names, options) {
+        let elem = meta.map_err(|err| err.label(path)).and_then(|meta| {
+            build_entry(
+                OsString::from_vec(name.as_bytes().to_vec()),
+                path,
+                meta,
+                || linux::stat_at(fd, &name, true, options),
+                context,
+                &mut err_collect,
+            )
+        });
+        match elem {
+            Ok(Some(elem)) => elems.push(elem),
+            Ok(None) => {}
+            Err(err) => err_collect(err),
+        }
+    }
+    finish_listing(path, listed, &mut elems, context, &mut err_collect);
+    elems
+}
+
+/// The metadata of the entries `names` of the directory open as `fd`, not
+/// following symbolic links, each along with its name.
+fn stat_batch(
+    fd: BorrowedFd,
+    names: Vec<CString>,
+    options: StatxOptions,
+) -> Vec<(CString, io::Result<Stat>)> {
+    let stat_each = |names: Vec<CString>| {
+        names
+            .into_iter()
+            .map(|name| {
+                let meta = linux::stat_at(fd, &name, false, options);
+                (name, meta)
+            })
+            .collect()
+    };
+    RING.with(|ring| {
+        let mut ring = ring.borrow_mut();
+        let Some(uring) = ring.as_mut() else {
+            return stat_each(names);
+        };
+        // SAFETY: statx is plain data, for which zeroes are valid
+        let mut bufs: Vec<libc::statx> = vec![unsafe { mem::zeroed() }; names.len()];
+        let mut results = vec![0; names.len()];
+        for start in (0..names.len()).step_by(RING_ENTRIES as usize) {
+            let end = (start + RING_ENTRIES as usize).min(names.len());
+            let chunk = submit_chunk(
+                uring,
+                fd,
+                &names[start..end],
+                &mut bufs[start..end],
+                &mut results[start..end],
+                start,
+                options,
+            );
+            if chunk.is_err() {
+                // Requests may still be in flight, reading the names and
+                // writing to the buffers, so neither may ever be freed
+                let copies = names.clone();
+                mem::forget(names);
+                mem::forget(bufs);
+                *ring = None;
+                return stat_each(copies);
+            }
+        }
+        names
+            .into_iter()
+            .zip(bufs.iter().zip(results))
+            .map(|(name, (buf, result))| {
+                let meta = if result < 0 {
+                    Err(io::Error::from_raw_os_error(-result))
+                } else {
+                    Ok(linux::stat_from_statx(buf))
+                };
+                (name, meta)
+            })
+            .collect()
+    })
+}
+
+/// Submits the stat calls for a chunk of at most `RING_ENTRIES` names and
+/// waits for all of them. `first` is the index of the chunk in the batch.
+fn submit_chunk(
+    uring: &mut IoUring,
+    fd: BorrowedFd,
+    names: &[CString],
+    bufs: &mut [libc::statx],
+    results: &mut [i32],
+    first: usize,
+    options: StatxOptions,
+) -> io::Result<()> {
+    for (i, (name, buf)) in names.iter().zip(bufs.iter_mut()).enumerate() {
+        let entry = opcode::Statx::new(
+            types::Fd(fd.as_raw_fd()),
+            name.as_ptr(),
+            (buf as *mut libc::statx).cast::<types::statx>(),
+        )
+        .flags(libc::AT_SYMLINK_NOFOLLOW | options.sync_flags)
+        .mask(options.mask)
+        .build()
+        .user_data((first + i) as u64);
+        // SAFETY: the name and buffer outlive the request, which is waited
+        // for below, or else leaked by `stat_batch`
+        unsafe { uring.submission().push(&entry) }.expect("a chunk fits in the submission queue");
+    }
+    let mut done = 0;
+    while done < names.len() {
+        match uring.submit_and_wait(names.len() - done) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+        for cqe in uring.completion() {
+            results[cqe.user_data() as usize - first] = cqe.result();
+            done += 1;
+        }
+    }
+    Ok(())
+}